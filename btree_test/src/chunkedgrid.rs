@@ -0,0 +1,369 @@
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::journal::DirtyChunks;
+use crate::savefile::{ChunkTiles, SaveChunks, SaveError};
+use crate::voxelstore::{btree_memory, Coord, VoxelStore};
+
+pub const SECTION_SIZE: u16 = 16;
+const SECTION_VOLUME: usize = 16 * 16 * 16;
+
+// voxel index inside a section, column major like NestedBTree so a z column is contiguous
+#[inline(always)]
+fn split(x: u16, y: u16, z: u8) -> ((u16, u16, u8), usize) {
+    let s = SECTION_SIZE;
+    let key = (x / s, y / s, z / s as u8);
+    let index = (((x % s) * s + y % s) * s) as usize + (z % s as u8) as usize;
+    (key, index)
+}
+
+#[inline(always)]
+fn join(key: (u16, u16, u8), index: usize) -> Coord {
+    let s = SECTION_SIZE as usize;
+    let lz = index % s;
+    let ly = (index / s) % s;
+    let lx = index / (s * s);
+    (
+        key.0 * SECTION_SIZE + lx as u16,
+        key.1 * SECTION_SIZE + ly as u16,
+        key.2 * SECTION_SIZE as u8 + lz as u8,
+    )
+}
+
+// 16x16x16 dense section, every voxel is a bit packed index into the palette.
+// palette slot 0 is always the empty tile, indices never straddle two words
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Section<T> {
+    palette: Vec<Option<T>>,
+    counts: Vec<u32>,
+    bits: u32,
+    data: Vec<u64>,
+    len: usize,
+    // voxel and slot get_mut last copied a shared value into, merged back on the next write
+    // if the value didn't change so a read through get_mut doesn't leave a duplicate behind
+    #[serde(skip)]
+    copied: Option<(usize, usize)>,
+}
+
+impl<T: Clone + PartialEq> Section<T> {
+    fn new() -> Section<T> {
+        Self {
+            palette: vec![None],
+            counts: vec![SECTION_VOLUME as u32],
+            bits: 1,
+            data: vec![0; SECTION_VOLUME.div_ceil(64)],
+            len: 0,
+            copied: None,
+        }
+    }
+    #[inline(always)]
+    fn index(&self, i: usize) -> usize {
+        let per_word = (64 / self.bits) as usize;
+        let word = self.data[i / per_word];
+        let shift = (i % per_word) as u32 * self.bits;
+        ((word >> shift) & ((1 << self.bits) - 1)) as usize
+    }
+    #[inline(always)]
+    fn set_index(&mut self, i: usize, value: usize) {
+        let per_word = (64 / self.bits) as usize;
+        let shift = (i % per_word) as u32 * self.bits;
+        let mask = ((1u64 << self.bits) - 1) << shift;
+        let word = &mut self.data[i / per_word];
+        *word = (*word & !mask) | ((value as u64) << shift);
+    }
+    fn repack(&mut self, bits: u32) {
+        let old = std::mem::replace(&mut self.data, vec![0; SECTION_VOLUME.div_ceil((64 / bits) as usize)]);
+        let old_bits = std::mem::replace(&mut self.bits, bits);
+        let old_per_word = (64 / old_bits) as usize;
+        let old_mask = (1u64 << old_bits) - 1;
+        for i in 0..SECTION_VOLUME {
+            let shift = (i % old_per_word) as u32 * old_bits;
+            let index = (old[i / old_per_word] >> shift) & old_mask;
+            if index != 0 {
+                self.set_index(i, index as usize);
+            }
+        }
+    }
+    // folds the slot get_mut copied into back into one holding the same value
+    fn settle(&mut self) {
+        let Some((i, copy)) = self.copied.take() else {
+            return;
+        };
+        let value = &self.palette[copy];
+        if let Some(slot) = (1..self.palette.len()).find(|&s| s != copy && self.palette[s] == *value) {
+            self.set_index(i, slot);
+            self.counts[slot] += 1;
+            self.counts[copy] = 0;
+            self.palette[copy] = None;
+        }
+    }
+    // an unused slot, the palette only grows once every slot holds a value
+    fn free_slot(&mut self) -> usize {
+        if let Some(slot) = self.counts.iter().skip(1).position(|c| *c == 0) {
+            return slot + 1;
+        }
+        if self.palette.len() == 1 << self.bits {
+            self.repack(self.bits + 1);
+        }
+        self.palette.push(None);
+        self.counts.push(0);
+        self.palette.len() - 1
+    }
+    // finds or allocates a palette slot for value, palettes are small so a scan is fine
+    fn slot_for(&mut self, value: T) -> usize {
+        self.settle();
+        if let Some(slot) = self.palette.iter().position(|p| p.as_ref() == Some(&value)) {
+            return slot;
+        }
+        let slot = self.free_slot();
+        self.palette[slot] = Some(value);
+        slot
+    }
+    // points voxel i at slot and hands back the value it used to hold
+    fn replace(&mut self, i: usize, slot: usize) -> Option<T> {
+        self.settle();
+        let old = self.index(i);
+        if old == slot {
+            return self.palette[old].clone();
+        }
+        self.set_index(i, slot);
+        self.counts[slot] += 1;
+        self.counts[old] -= 1;
+        if slot != 0 && old == 0 {
+            self.len += 1;
+        } else if slot == 0 && old != 0 {
+            self.len -= 1;
+        }
+        if old != 0 && self.counts[old] == 0 {
+            self.palette[old].take()
+        } else {
+            self.palette[old].clone()
+        }
+    }
+    fn get(&self, i: usize) -> Option<&T> {
+        self.palette[self.index(i)].as_ref()
+    }
+    // a shared palette entry gets its own copy first so the write only touches voxel i
+    fn get_mut(&mut self, i: usize) -> Option<&mut T> {
+        self.settle();
+        let mut slot = self.index(i);
+        if slot == 0 {
+            return None;
+        }
+        if self.counts[slot] > 1 {
+            let copy = self.free_slot();
+            self.palette[copy] = self.palette[slot].clone();
+            self.counts[slot] -= 1;
+            self.counts[copy] += 1;
+            self.set_index(i, copy);
+            self.copied = Some((i, copy));
+            slot = copy;
+        }
+        self.palette[slot].as_mut()
+    }
+    fn iter(&self) -> impl Iterator<Item = (usize, &T)> + '_ {
+        (0..SECTION_VOLUME).filter_map(move |i| self.get(i).map(|v| (i, v)))
+    }
+    // the vectors' heap, the section itself lives in the grid's tree
+    fn memory_usage(&self) -> usize {
+        self.palette.capacity() * std::mem::size_of::<Option<T>>()
+            + self.counts.capacity() * std::mem::size_of::<u32>()
+            + self.data.capacity() * std::mem::size_of::<u64>()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunkedGrid<T> {
+    sections: BTreeMap<(u16, u16, u8), Section<T>>,
+    len: usize,
+    // sections line up with the 16x16 save chunks, so a chunk is a stack of 16 sections
+    #[serde(skip)]
+    dirty: BTreeSet<(u16, u16)>,
+}
+
+impl<T: Clone + PartialEq> ChunkedGrid<T> {
+    pub fn new() -> ChunkedGrid<T> {
+        Self {
+            sections: BTreeMap::new(),
+            len: 0,
+            dirty: BTreeSet::new(),
+        }
+    }
+    pub fn section_count(&self) -> usize {
+        self.sections.len()
+    }
+}
+
+impl<T: Clone + PartialEq + Send> ChunkedGrid<T> {
+    // groups the tiles by section and fills the sections on the rayon pool, a tile given twice
+    // keeps the later value. returns how many tiles are new
+    pub fn par_insert_batch(&mut self, tiles: Vec<(Coord, T)>) -> usize {
+        let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for ((x, y, z), value) in tiles {
+            let (key, i) = split(x, y, z);
+            groups.entry(key).or_default().push((i, value));
+        }
+        // sections are taken out while they're filled so every worker owns the one it's on
+        let jobs: Vec<_> = groups.into_iter()
+            .map(|(key, tiles)| (key, self.sections.remove(&key).unwrap_or_else(Section::new), tiles))
+            .collect();
+        let filled: Vec<_> = jobs.into_par_iter()
+            .map(|(key, mut section, tiles)| {
+                let mut added = 0;
+                for (i, value) in tiles {
+                    let slot = section.slot_for(value);
+                    if section.replace(i, slot).is_none() {
+                        added += 1;
+                    }
+                }
+                (key, section, added)
+            })
+            .collect();
+        let mut total = 0;
+        for (key, section, added) in filled {
+            self.sections.insert(key, section);
+            self.dirty.insert((key.0, key.1));
+            total += added;
+        }
+        self.len += total;
+        total
+    }
+}
+
+impl<T: Clone + PartialEq + Sync> ChunkedGrid<T> {
+    // every tile with the sections split over the rayon pool, collecting keeps section by section order
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (Coord, &T)> + '_ {
+        self.sections.par_iter().flat_map_iter(|(&key, section)| {
+            section.iter().map(move |(i, v)| (join(key, i), v))
+        })
+    }
+}
+
+impl<T: Clone + PartialEq> Default for ChunkedGrid<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStore<T> for ChunkedGrid<T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        let (key, i) = split(x, y, z);
+        self.sections.get(&key)?.get(i)
+    }
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        let (key, i) = split(x, y, z);
+        let value = self.sections.get_mut(&key)?.get_mut(i)?;
+        self.dirty.insert((key.0, key.1));
+        Some(value)
+    }
+    #[inline(always)]
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        let (key, i) = split(x, y, z);
+        self.dirty.insert((key.0, key.1));
+        // sections are only allocated once something is written to them
+        let section = self.sections.entry(key).or_insert_with(Section::new);
+        let slot = section.slot_for(value);
+        let old = section.replace(i, slot);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        let (key, i) = split(x, y, z);
+        let section = self.sections.get_mut(&key)?;
+        let old = section.replace(i, 0)?;
+        self.dirty.insert((key.0, key.1));
+        if section.len == 0 {
+            self.sections.remove(&key);
+        }
+        self.len -= 1;
+        Some(old)
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(self.sections.iter().flat_map(|(key, section)| {
+            section.iter().map(move |(i, v)| (join(*key, i), v))
+        }))
+    }
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + btree_memory::<(u16, u16, u8), Section<T>>(self.sections.len())
+            + self.sections.values().map(Section::memory_usage).sum::<usize>()
+            + btree_memory::<(u16, u16), ()>(self.dirty.len())
+    }
+}
+
+impl<T: Clone + PartialEq> DirtyChunks<T> for ChunkedGrid<T> {
+    fn dirty_chunks(&self) -> Vec<(u16, u16)> {
+        self.dirty.iter().copied().collect()
+    }
+    fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+    fn chunk_tiles(&self, chunk: (u16, u16)) -> ChunkTiles<'_, T> {
+        let s = SECTION_SIZE as usize;
+        let mut tiles: ChunkTiles<'_, T> = self.sections.range((chunk.0, chunk.1, 0)..=(chunk.0, chunk.1, u8::MAX))
+            .flat_map(|(key, section)| {
+                section.iter().map(move |(i, v)| ((i / s) as u8, (key.2 as usize * s + i % s) as u8, v))
+            })
+            .collect();
+        tiles.sort_unstable_by_key(|t| (t.0, t.1));
+        tiles
+    }
+}
+
+impl<T: Clone + PartialEq> SaveChunks<T> for ChunkedGrid<T> {
+    fn for_each_chunk<'a, F>(&'a self, mut f: F) -> Result<(), SaveError>
+    where
+        T: 'a,
+        F: FnMut((u16, u16), ChunkTiles<'a, T>) -> Result<(), SaveError>,
+    {
+        let mut last = None;
+        for &(cx, cy, _) in self.sections.keys() {
+            if last != Some((cx, cy)) {
+                last = Some((cx, cy));
+                f((cx, cy), self.chunk_tiles((cx, cy)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_get_mut_leaves_no_duplicates() {
+        let mut section: Section<u32> = Section::new();
+        for i in 0..SECTION_VOLUME {
+            let slot = section.slot_for(i as u32 % 3);
+            section.replace(i, slot);
+        }
+        for i in 0..SECTION_VOLUME {
+            section.get_mut(i).unwrap();
+        }
+        // three values plus the one slot a copy takes until the next write merges it back
+        section.settle();
+        assert_eq!(section.palette.len(), 5);
+        assert_eq!(section.palette.iter().flatten().count(), 3);
+        *section.get_mut(7).unwrap() = 2;
+        *section.get_mut(8).unwrap() = 5;
+        *section.get_mut(9).unwrap() += 0;
+        section.settle();
+        assert_eq!(section.palette.iter().flatten().count(), 4);
+        for i in 0..SECTION_VOLUME {
+            let expected = match i {
+                7 => 2,
+                8 => 5,
+                _ => i as u32 % 3,
+            };
+            assert_eq!(section.get(i), Some(&expected), "{}", i);
+        }
+        assert_eq!(section.counts.iter().sum::<u32>(), SECTION_VOLUME as u32);
+    }
+}
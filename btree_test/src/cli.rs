@@ -0,0 +1,204 @@
+use btree_test::compress::Compression;
+use std::path::PathBuf;
+use crate::mixed::Mix;
+use crate::pattern::AccessPattern;
+use crate::report::Format;
+use crate::TestType;
+
+pub struct Options {
+    pub backends: Vec<TestType>,
+    pub iterations: u32,
+    pub width: u16,
+    pub serialize: bool,
+    pub pattern: AccessPattern,
+    // every random pattern and check is seeded from this so runs repeat exactly
+    pub seed: u64,
+    // run the mixed op workload instead of bulk insert then compare
+    pub mix: Option<Mix>,
+    pub repeat: u32,
+    pub compression: Compression,
+    // reference, journal and codec checks before the backend runs
+    pub extras: bool,
+    // results file, format picked from the extension unless given
+    pub output: Option<PathBuf>,
+    pub format: Option<Format>,
+    // earlier results file to check this run against
+    pub compare: Option<PathBuf>,
+    // percent slower (or bigger on disk) than the baseline that counts as a regression
+    pub threshold: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            // generic map is left out, its inserts shift the whole vector
+            backends: TestType::ALL.iter().copied().filter(|t| *t != TestType::GenericMap).collect(),
+            iterations: 2_000_000,
+            width: 64000,
+            serialize: true,
+            pattern: AccessPattern::Sweep,
+            seed: 0xDEADF00D,
+            mix: None,
+            repeat: 5,
+            // strongest codec this build has, none unless built with --features lz4 or zstd
+            compression: *Compression::available().last().unwrap(),
+            extras: true,
+            output: None,
+            format: None,
+            compare: None,
+            threshold: 10.0,
+        }
+    }
+}
+
+pub enum Command {
+    Run(Options),
+    Help,
+}
+
+fn keys<T: Copy>(all: &[T], key: impl Fn(T) -> &'static str) -> String {
+    all.iter().map(|t| key(*t)).collect::<Vec<_>>().join(", ")
+}
+
+pub fn usage() -> String {
+    let defaults = Options::default();
+    let codecs = keys(&Compression::available(), Compression::name);
+    format!(
+        "usage: btree_test [options]
+  --backends <list>     comma separated, or all: {}
+                        (default: {})
+  --iterations <n>      tiles inserted per run (default: {})
+  --width <n>           map width and height in tiles (default: {})
+  --serialize <on|off>  save and reload the map every run (default: on)
+  --pattern <name>      access pattern: {} (default: {})
+  --seed <n>            seed for the random patterns and checks (default: {})
+  --workload <name>     bulk: insert everything then read it back, mixed: prefill from the
+                        pattern then run that many interleaved ops (default: bulk)
+  --mix <weights>       op weights for the mixed workload, implies it (default: {})
+  --repeat <n>          runs per backend (default: {})
+  --compression <name>  save compression: {} (default: {})
+  --no-extras           skip the reference, journal and codec checks
+  --output <path>       write the results to a file as well
+  --format <name>       results file format: {} (default: from the extension, json otherwise)
+  --compare <path>      flag regressions against an earlier results file, exits with 1 if any
+  --threshold <pct>     percent slower or bigger that counts as a regression (default: {})
+  -h, --help            print this and exit",
+        keys(&TestType::ALL, TestType::key),
+        keys(&defaults.backends, TestType::key),
+        defaults.iterations,
+        defaults.width,
+        keys(&AccessPattern::ALL, AccessPattern::key),
+        defaults.pattern.key(),
+        defaults.seed,
+        Mix::default().key(),
+        defaults.repeat,
+        codecs,
+        defaults.compression.name(),
+        keys(&Format::ALL, Format::key),
+        defaults.threshold,
+    )
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, got '{}'", flag, value))
+}
+
+fn lookup<T: Copy>(flag: &str, all: &[T], key: impl Fn(T) -> &'static str, value: &str) -> Result<T, String> {
+    all.iter().copied().find(|t| key(*t) == value)
+        .ok_or_else(|| format!("unknown {} '{}', expected one of: {}", flag, value, keys(all, key)))
+}
+
+// args without the program name, flags take their value as the next arg or after '='
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--no-extras" => {
+                options.extras = false;
+                continue;
+            }
+            _ => {}
+        }
+        let value = match inline.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(format!("{} needs a value", flag)),
+        };
+        match flag.as_str() {
+            "--backends" => {
+                options.backends = if value == "all" {
+                    TestType::ALL.to_vec()
+                } else {
+                    value.split(',')
+                        .map(|name| lookup("backend", &TestType::ALL, TestType::key, name.trim()))
+                        .collect::<Result<_, _>>()?
+                };
+            }
+            "--iterations" => options.iterations = number(&flag, &value)?,
+            "--width" => options.width = number(&flag, &value)?,
+            "--serialize" => {
+                options.serialize = match value.as_str() {
+                    "on" | "true" | "yes" => true,
+                    "off" | "false" | "no" => false,
+                    _ => return Err(format!("--serialize expects on or off, got '{}'", value)),
+                };
+            }
+            "--pattern" => options.pattern = lookup("pattern", &AccessPattern::ALL, AccessPattern::key, &value)?,
+            "--seed" => options.seed = number(&flag, &value)?,
+            "--workload" => {
+                options.mix = match value.as_str() {
+                    "bulk" => None,
+                    "mixed" => Some(options.mix.unwrap_or_default()),
+                    _ => return Err(format!("unknown workload '{}', expected one of: bulk, mixed", value)),
+                };
+            }
+            "--mix" => options.mix = Some(Mix::parse(&value)?),
+            "--repeat" => options.repeat = number(&flag, &value)?,
+            "--compression" => {
+                options.compression = lookup("compression", &Compression::available(), Compression::name, &value)?;
+            }
+            "--output" => options.output = Some(PathBuf::from(value)),
+            "--format" => options.format = Some(lookup("format", &Format::ALL, Format::key, &value)?),
+            "--compare" => options.compare = Some(PathBuf::from(value)),
+            "--threshold" => options.threshold = number(&flag, &value)?,
+            _ => return Err(format!("unknown option '{}'", flag)),
+        }
+    }
+    if options.repeat == 0 || options.width == 0 {
+        return Err("--repeat and --width have to be at least 1".to_string());
+    }
+    // the sweep goes row by row and would run off the bottom of the map,
+    // and the extras always sweep whatever pattern the backends use
+    let area = options.width as u64 * options.width as u64;
+    if (options.pattern == AccessPattern::Sweep || options.extras) && options.iterations as u64 > area {
+        return Err(format!(
+            "--iterations {} is more than the {} tiles a {}x{} sweep covers (the extras always sweep, see --no-extras)",
+            options.iterations, area, options.width, options.width
+        ));
+    }
+    Ok(Command::Run(options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn sweep_has_to_fit_the_map() {
+        assert!(run(&["--iterations", "700000", "--width", "10"]).is_err());
+        assert!(run(&["--iterations", "101", "--width", "10"]).is_err());
+        assert!(run(&["--iterations", "100", "--width", "10"]).is_ok());
+        assert!(run(&["--iterations", "700000", "--width", "10", "--pattern", "random"]).is_err());
+        assert!(run(&["--iterations", "700000", "--width", "10", "--pattern", "random", "--no-extras"]).is_ok());
+        assert!(run(&["--iterations", "700000", "--width", "10", "--no-extras"]).is_err());
+        assert!(run(&["--iterations", "4294836225", "--width", "65535"]).is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use crate::savefile::SaveError;
+
+// how a chunk record's tiles turn into payload bytes. the record framing, crc and counts
+// around the payload are the same for every codec, the header says which one wrote the file
+pub trait Codec {
+    const ID: u8;
+    const NAME: &'static str;
+}
+
+pub trait Encode<T>: Codec {
+    // tiles come in (column, z) order
+    fn encode(tiles: &[(u8, u8, &T)], out: &mut Vec<u8>) -> Result<(), SaveError>;
+}
+
+pub trait Decode<T>: Codec {
+    fn decode(payload: &[u8]) -> Result<Vec<(u8, u8, T)>, SaveError>;
+}
+
+pub struct Bincode;
+pub struct Postcard;
+// column u8, z u8, value as fixed size little endian, repeated
+pub struct LittleEndian;
+// see ArchivedChunk
+pub struct Archive;
+
+impl Codec for Bincode {
+    const ID: u8 = 0;
+    const NAME: &'static str = "bincode";
+}
+impl Codec for Postcard {
+    const ID: u8 = 1;
+    const NAME: &'static str = "postcard";
+}
+impl Codec for LittleEndian {
+    const ID: u8 = 2;
+    const NAME: &'static str = "little endian";
+}
+impl Codec for Archive {
+    const ID: u8 = 3;
+    const NAME: &'static str = "archive";
+}
+
+impl<T: Serialize> Encode<T> for Bincode {
+    fn encode(tiles: &[(u8, u8, &T)], out: &mut Vec<u8>) -> Result<(), SaveError> {
+        bincode::serialize_into(out, tiles)?;
+        Ok(())
+    }
+}
+impl<T: DeserializeOwned> Decode<T> for Bincode {
+    fn decode(payload: &[u8]) -> Result<Vec<(u8, u8, T)>, SaveError> {
+        Ok(bincode::deserialize(payload)?)
+    }
+}
+
+impl<T: Serialize> Encode<T> for Postcard {
+    fn encode(tiles: &[(u8, u8, &T)], out: &mut Vec<u8>) -> Result<(), SaveError> {
+        out.extend_from_slice(&postcard::to_stdvec(tiles).map_err(|e| SaveError::Corrupt(e.to_string()))?);
+        Ok(())
+    }
+}
+impl<T: DeserializeOwned> Decode<T> for Postcard {
+    fn decode(payload: &[u8]) -> Result<Vec<(u8, u8, T)>, SaveError> {
+        postcard::from_bytes(payload).map_err(|e| SaveError::Corrupt(e.to_string()))
+    }
+}
+
+// values with a fixed size little endian form, for the hand rolled codecs
+pub trait LeValue: Sized {
+    const SIZE: usize;
+    fn write_le(&self, out: &mut Vec<u8>);
+    // bytes is exactly SIZE long
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+macro_rules! le_value {
+    ($($t:ty),*) => {
+        $(
+            impl LeValue for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+                fn write_le(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+                fn read_le(bytes: &[u8]) -> Self {
+                    <$t>::from_le_bytes(bytes.try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+le_value!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<T: LeValue> Encode<T> for LittleEndian {
+    fn encode(tiles: &[(u8, u8, &T)], out: &mut Vec<u8>) -> Result<(), SaveError> {
+        out.reserve(tiles.len() * (2 + T::SIZE));
+        for (column, z, value) in tiles {
+            out.push(*column);
+            out.push(*z);
+            value.write_le(out);
+        }
+        Ok(())
+    }
+}
+impl<T: LeValue> Decode<T> for LittleEndian {
+    fn decode(payload: &[u8]) -> Result<Vec<(u8, u8, T)>, SaveError> {
+        let stride = 2 + T::SIZE;
+        if !payload.len().is_multiple_of(stride) {
+            return Err(SaveError::Corrupt(format!("payload of {} bytes isn't a whole number of tiles", payload.len())));
+        }
+        Ok(payload.chunks_exact(stride).map(|t| (t[0], t[1], T::read_le(&t[2..]))).collect())
+    }
+}
+
+impl<T: LeValue> Encode<T> for Archive {
+    fn encode(tiles: &[(u8, u8, &T)], out: &mut Vec<u8>) -> Result<(), SaveError> {
+        out.reserve(tiles.len() * (2 + T::SIZE));
+        for (column, z, _) in tiles {
+            out.extend_from_slice(&archive_key(*column, *z).to_le_bytes());
+        }
+        for (_, _, value) in tiles {
+            value.write_le(out);
+        }
+        Ok(())
+    }
+}
+impl<T: LeValue> Decode<T> for Archive {
+    fn decode(payload: &[u8]) -> Result<Vec<(u8, u8, T)>, SaveError> {
+        Ok(ArchivedChunk::new(payload)?.iter().collect())
+    }
+}
+
+#[inline(always)]
+fn archive_key(column: u8, z: u8) -> u16 {
+    (column as u16) << 8 | z as u16
+}
+
+// read only view straight over an archive payload, nothing is decoded up front.
+// layout: every tile's key (column << 8 | z) as u16 le in ascending order, then every value
+// in the same order, so a lookup is a binary search over the keys
+pub struct ArchivedChunk<'a, T> {
+    keys: &'a [u8],
+    values: &'a [u8],
+    value: PhantomData<T>,
+}
+
+// just two slices, derive would want T: Copy
+impl<T> Clone for ArchivedChunk<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T> Copy for ArchivedChunk<'_, T> {}
+
+impl<'a, T: LeValue> ArchivedChunk<'a, T> {
+    pub fn new(payload: &'a [u8]) -> Result<ArchivedChunk<'a, T>, SaveError> {
+        let stride = 2 + T::SIZE;
+        if !payload.len().is_multiple_of(stride) {
+            return Err(SaveError::Corrupt(format!("archive of {} bytes isn't a whole number of tiles", payload.len())));
+        }
+        let (keys, values) = payload.split_at(payload.len() / stride * 2);
+        Ok(Self { keys, values, value: PhantomData })
+    }
+    pub fn len(&self) -> usize {
+        self.keys.len() / 2
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+    #[inline(always)]
+    fn key(&self, i: usize) -> u16 {
+        u16::from_le_bytes([self.keys[i * 2], self.keys[i * 2 + 1]])
+    }
+    #[inline(always)]
+    fn value(&self, i: usize) -> T {
+        T::read_le(&self.values[i * T::SIZE..(i + 1) * T::SIZE])
+    }
+    pub fn get(&self, column: u8, z: u8) -> Option<T> {
+        let key = archive_key(column, z);
+        let (mut lo, mut hi) = (0, self.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.key(mid).cmp(&key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(self.value(mid)),
+            }
+        }
+        None
+    }
+    // (column, z, value) in key order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u8, T)> + 'a
+    where
+        T: 'a,
+    {
+        let chunk = *self;
+        (0..self.len()).map(move |i| {
+            let key = chunk.key(i);
+            ((key >> 8) as u8, key as u8, chunk.value(i))
+        })
+    }
+}
@@ -0,0 +1,194 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::voxelstore::{btree_memory, Coord, VoxelStore};
+
+// inclusive run of identical tiles, z = the start in its key..=end
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Run<T> {
+    end: u8,
+    value: T,
+}
+
+// every run is keyed by its column and the z it starts at, so finding, splitting and merging
+// runs is a few tree lookups. runs in a column never overlap, gaps are empty tiles
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ColumnRle<T> {
+    runs: BTreeMap<Coord, Run<T>>,
+    len: usize,
+    // the one tile run get_mut last handed out, merged with its neighbours on the next write
+    #[serde(skip)]
+    split: Option<Coord>,
+}
+
+impl<T: Clone + PartialEq> ColumnRle<T> {
+    pub fn new() -> ColumnRle<T> {
+        Self {
+            runs: BTreeMap::new(),
+            len: 0,
+            split: None,
+        }
+    }
+    pub fn run_count(&self) -> usize {
+        self.runs.len()
+    }
+    // (start, end, value) runs of one column, bottom up
+    pub fn runs(&self, x: u16, y: u16) -> impl Iterator<Item = (u8, u8, &T)> + '_ {
+        self.runs.range((x, y, 0)..=(x, y, u8::MAX)).map(|(&(_, _, start), r)| (start, r.end, &r.value))
+    }
+    // start of the run covering x, y, z
+    #[inline(always)]
+    fn find(&self, x: u16, y: u16, z: u8) -> Option<u8> {
+        let (&(rx, ry, start), run) = self.runs.range(..=(x, y, z)).next_back()?;
+        ((rx, ry) == (x, y) && run.end >= z).then_some(start)
+    }
+    // cuts z out of whatever run covers it and returns the old value
+    fn carve(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        let start = self.find(x, y, z)?;
+        let run = self.runs.remove(&(x, y, start)).unwrap();
+        if start < z {
+            self.runs.insert((x, y, start), Run { end: z - 1, value: run.value.clone() });
+        }
+        if run.end > z {
+            self.runs.insert((x, y, z + 1), Run { end: run.end, value: run.value.clone() });
+        }
+        Some(run.value)
+    }
+    // joins the run starting at x, y, start with its neighbours when they touch and hold the same tile
+    fn merge_around(&mut self, x: u16, y: u16, start: u8) {
+        let Some(run) = self.runs.get(&(x, y, start)) else {
+            return;
+        };
+        let mut end = run.end;
+        if end < u8::MAX && self.runs.get(&(x, y, end + 1)).is_some_and(|next| next.value == run.value) {
+            end = self.runs.remove(&(x, y, end + 1)).unwrap().end;
+            self.runs.get_mut(&(x, y, start)).unwrap().end = end;
+        }
+        if let Some(before) = start.checked_sub(1).and_then(|z| self.find(x, y, z)) {
+            if self.runs[&(x, y, before)].value == self.runs[&(x, y, start)].value {
+                self.runs.remove(&(x, y, start));
+                self.runs.get_mut(&(x, y, before)).unwrap().end = end;
+            }
+        }
+    }
+    fn settle(&mut self) {
+        if let Some((x, y, z)) = self.split.take() {
+            self.merge_around(x, y, z);
+        }
+    }
+    fn set(&mut self, x: u16, y: u16, z: u8, value: Option<T>) -> Option<T> {
+        self.settle();
+        if let (Some(start), Some(v)) = (self.find(x, y, z), &value) {
+            if self.runs[&(x, y, start)].value == *v {
+                return value;
+            }
+        }
+        let old = self.carve(x, y, z);
+        if let Some(value) = value {
+            self.runs.insert((x, y, z), Run { end: z, value });
+            self.merge_around(x, y, z);
+            if old.is_none() {
+                self.len += 1;
+            }
+        } else if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+}
+
+impl<T: Clone + PartialEq> Default for ColumnRle<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStore<T> for ColumnRle<T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        let start = self.find(x, y, z)?;
+        Some(&self.runs[&(x, y, start)].value)
+    }
+    // the tile gets a run of its own so the write can't leak into the rest of the run,
+    // the next write merges it back with its neighbours if they still match
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        self.settle();
+        let start = self.find(x, y, z)?;
+        if start != z || self.runs[&(x, y, z)].end != z {
+            let value = self.carve(x, y, z)?;
+            self.runs.insert((x, y, z), Run { end: z, value });
+        }
+        self.split = Some((x, y, z));
+        self.runs.get_mut(&(x, y, z)).map(|r| &mut r.value)
+    }
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        self.set(x, y, z, Some(value))
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        self.set(x, y, z, None)
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(self.runs.iter().flat_map(|(&(x, y, start), r)| {
+            (start..=r.end).map(move |z| ((x, y, z), &r.value))
+        }))
+    }
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + btree_memory::<Coord, Run<T>>(self.runs.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // runs a column would have with every touching pair of equal tiles merged
+    fn fewest_runs(flat: &BTreeMap<Coord, u8>) -> usize {
+        let mut last: Option<(Coord, u8)> = None;
+        let mut runs = 0;
+        for (&(x, y, z), &v) in flat {
+            if last != Some(((x, y, z.wrapping_sub(1)), v)) || z == 0 {
+                runs += 1;
+            }
+            last = Some(((x, y, z), v));
+        }
+        runs
+    }
+
+    #[test]
+    fn matches_flat_btree_and_stays_merged() {
+        let mut rng = StdRng::seed_from_u64(0xC01);
+        let mut rle: ColumnRle<u8> = ColumnRle::new();
+        let mut flat: BTreeMap<Coord, u8> = BTreeMap::new();
+        for step in 0..20_000 {
+            let (x, y, z) = (rng.gen_range(0..4), rng.gen_range(0..4), rng.gen_range(0..64));
+            let v = rng.gen_range(0..3);
+            match rng.gen_range(0..4) {
+                0 => assert_eq!(rle.remove(x, y, z), flat.remove(&(x, y, z))),
+                1 => {
+                    // mostly a read through, a third of the time a write
+                    let got = rle.get_mut(x, y, z).map(|t| {
+                        if v == 0 {
+                            *t = 2;
+                        }
+                        *t
+                    });
+                    assert_eq!(got.is_some(), flat.contains_key(&(x, y, z)));
+                    if let Some(t) = got {
+                        flat.insert((x, y, z), t);
+                    }
+                }
+                _ => assert_eq!(rle.insert(x, y, z, v), flat.insert((x, y, z), v)),
+            }
+            if step % 64 == 0 {
+                rle.settle();
+                assert_eq!(rle.len(), flat.len());
+                assert!(rle.iter().map(|(c, v)| (c, *v)).eq(flat.iter().map(|(c, v)| (*c, *v))));
+                assert_eq!(rle.run_count(), fewest_runs(&flat));
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::borrow::Cow;
+#[cfg(feature = "lz4")]
+use std::io::{Read, Write};
+use crate::savefile::SaveError;
+
+// a compressed save is this magic, a codec id byte, then an ordinary save file run through the
+// codec. uncompressed saves are written as they always were so old files still load
+pub const COMPRESSED_MAGIC: [u8; 4] = *b"DDMZ";
+
+// codecs only exist when their cargo feature is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    // zstd level, 1..=22
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => 2,
+        }
+    }
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => "lz4",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => "zstd",
+        }
+    }
+    // everything this build was compiled with, cheapest first
+    pub fn available() -> Vec<Compression> {
+        #[allow(unused_mut)]
+        let mut codecs = vec![Compression::None];
+        #[cfg(feature = "lz4")]
+        codecs.push(Compression::Lz4);
+        #[cfg(feature = "zstd")]
+        codecs.push(Compression::Zstd(3));
+        codecs
+    }
+}
+
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+fn prefix(compression: Compression) -> Vec<u8> {
+    let mut out = COMPRESSED_MAGIC.to_vec();
+    out.push(compression.id());
+    out
+}
+
+// compresses a whole save file that's already in memory
+pub fn encode(compression: Compression, raw: &[u8]) -> Result<Vec<u8>, SaveError> {
+    match compression {
+        Compression::None => Ok(raw.to_vec()),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(prefix(compression));
+            encoder.write_all(raw)?;
+            Ok(encoder.finish().map_err(std::io::Error::from)?)
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd(level) => {
+            let mut out = prefix(compression);
+            out.extend_from_slice(&zstd::encode_all(raw, level)?);
+            Ok(out)
+        }
+    }
+}
+
+// undoes encode, a plain save file comes back untouched
+pub fn decode(bytes: &[u8]) -> Result<Cow<'_, [u8]>, SaveError> {
+    if bytes.len() < 5 || bytes[0..4] != COMPRESSED_MAGIC {
+        return Ok(Cow::Borrowed(bytes));
+    }
+    #[allow(unused_variables)]
+    let body = &bytes[5..];
+    match bytes[4] {
+        #[cfg(feature = "lz4")]
+        1 => {
+            let mut raw = Vec::new();
+            lz4_flex::frame::FrameDecoder::new(body).read_to_end(&mut raw)?;
+            Ok(Cow::Owned(raw))
+        }
+        #[cfg(feature = "zstd")]
+        2 => Ok(Cow::Owned(zstd::decode_all(body)?)),
+        other => Err(SaveError::UnsupportedCompression(other)),
+    }
+}
@@ -1,12 +1,15 @@
 use serde::{Deserialize, Serialize};
+use std::ops::{Bound, RangeBounds};
+use crate::voxelstore::{Coord, VoxelStore};
 
+// sorted vector map, keys are kept ascending so every lookup is a binary search
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GenericMap<K, V> {
     keys: Vec<K>,
     vals: Vec<V>,
 }
 
-impl<K: PartialEq + PartialOrd, V> GenericMap<K, V> {
+impl<K: Ord, V> GenericMap<K, V> {
     pub fn new() -> GenericMap<K, V> {
         Self {
             keys: Vec::new(),
@@ -19,30 +22,174 @@ impl<K: PartialEq + PartialOrd, V> GenericMap<K, V> {
             vals: Vec::with_capacity(capacity),
         }
     }
-    pub fn insert(&mut self, key: K, value: V) {
-        let index = self.keys.iter().position(|p| p == &key);
-        if index.is_some() {
-            self.vals[index.unwrap()] = value;
-        } else {
-            let mut index = 0;
-            loop {
-                if index == self.keys.len() {
-                    break;
+    // builds the map in one pass from ascending keys, a repeated key keeps the last value
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> GenericMap<K, V> {
+        let iter = iter.into_iter();
+        let mut map = Self::with_capacity(iter.size_hint().0);
+        for (key, value) in iter {
+            match map.keys.last() {
+                Some(last) if *last == key => {
+                    *map.vals.last_mut().unwrap() = value;
                 }
-                if self.keys[index] < key {
-                    break;
+                Some(last) => {
+                    assert!(*last < key, "from_sorted_iter needs ascending keys");
+                    map.keys.push(key);
+                    map.vals.push(value);
                 }
-                index += 1;
+                None => {
+                    map.keys.push(key);
+                    map.vals.push(value);
+                }
+            }
+        }
+        map
+    }
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        match self.keys.binary_search(&key) {
+            Ok(index) => Some(std::mem::replace(&mut self.vals[index], value)),
+            Err(index) => {
+                self.keys.insert(index, key);
+                self.vals.insert(index, value);
+                None
             }
-            self.keys.insert(index, key);
-            self.vals.insert(index, value);
         }
     }
     pub fn get(&self, key: &K) -> Option<&V> {
-        let index = match self.keys.iter().position(|p| p == key) {
-            None => return None,
-            Some(s) => s,
-        };
+        let index = self.keys.binary_search(key).ok()?;
         Some(&self.vals[index])
     }
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let index = self.keys.binary_search(key).ok()?;
+        Some(&mut self.vals[index])
+    }
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.keys.binary_search(key).ok()?;
+        self.keys.remove(index);
+        Some(self.vals.remove(index))
+    }
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.keys.binary_search(key).is_ok()
+    }
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+    // ascending key order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.keys.iter().zip(self.vals.iter())
+    }
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&K, &V)> {
+        let start = match range.start_bound() {
+            Bound::Included(k) => self.keys.partition_point(|p| p < k),
+            Bound::Excluded(k) => self.keys.partition_point(|p| p <= k),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => self.keys.partition_point(|p| p <= k),
+            Bound::Excluded(k) => self.keys.partition_point(|p| p < k),
+            Bound::Unbounded => self.keys.len(),
+        };
+        let end = end.max(start);
+        self.keys[start..end].iter().zip(self.vals[start..end].iter())
+    }
+}
+
+impl<K: Ord, V> Default for GenericMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> VoxelStore<T> for GenericMap<Coord, T> {
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        GenericMap::get(self, &(x, y, z))
+    }
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        GenericMap::get_mut(self, &(x, y, z))
+    }
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        GenericMap::insert(self, (x, y, z), value)
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        GenericMap::remove(self, &(x, y, z))
+    }
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T {
+        let index = match self.keys.binary_search(&(x, y, z)) {
+            Ok(index) => index,
+            Err(index) => {
+                self.keys.insert(index, (x, y, z));
+                self.vals.insert(index, default());
+                index
+            }
+        };
+        &mut self.vals[index]
+    }
+    fn contains(&self, x: u16, y: u16, z: u8) -> bool {
+        self.contains_key(&(x, y, z))
+    }
+    fn len(&self) -> usize {
+        GenericMap::len(self)
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(GenericMap::iter(self).map(|(k, v)| (*k, v)))
+    }
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.keys.capacity() * std::mem::size_of::<Coord>()
+            + self.vals.capacity() * std::mem::size_of::<T>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+
+    #[test]
+    fn range_matches_btree() {
+        let mut rng = StdRng::seed_from_u64(0x6E4);
+        let reference: BTreeMap<u16, u32> = (0..2000).map(|_| (rng.gen_range(0..4000), rng.gen())).collect();
+        let map = GenericMap::from_sorted_iter(reference.iter().map(|(k, v)| (*k, *v)));
+        assert!(map.iter().eq(reference.iter()));
+        let bound = |rng: &mut StdRng| match rng.gen_range(0..4) {
+            0 => Unbounded,
+            1 => Included(rng.gen_range(0..=u16::MAX)),
+            2 => Excluded(rng.gen_range(0..4000)),
+            _ => Included(rng.gen_range(0..4000)),
+        };
+        for _ in 0..1000 {
+            let (start, end) = (bound(&mut rng), bound(&mut rng));
+            // BTreeMap panics on these, the sorted map hands back nothing
+            let inverted = match (start, end) {
+                (Included(a) | Excluded(a), Included(b) | Excluded(b)) => a > b || a == b && (start != Included(a) || end != Included(b)),
+                _ => false,
+            };
+            let got: Vec<(&u16, &u32)> = map.range((start, end)).collect();
+            if inverted {
+                assert!(got.is_empty(), "{:?}..{:?}", start, end);
+            } else {
+                assert_eq!(got, reference.range((start, end)).collect::<Vec<_>>(), "{:?}..{:?}", start, end);
+            }
+        }
+        assert_eq!(map.range(..).count(), reference.len());
+        assert_eq!(map.range(u16::MAX..).count(), 0);
+    }
+
+    #[test]
+    fn from_sorted_iter_keeps_the_last_repeat() {
+        let map = GenericMap::from_sorted_iter([(1, 'a'), (1, 'b'), (2, 'c'), (5, 'd'), (5, 'e')]);
+        assert_eq!(map.iter().collect::<Vec<_>>(), [(&1, &'b'), (&2, &'c'), (&5, &'e')]);
+        assert!(GenericMap::<u8, u8>::from_sorted_iter([]).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "ascending")]
+    fn from_sorted_iter_rejects_descending_keys() {
+        GenericMap::from_sorted_iter([(2, ()), (1, ())]);
+    }
 }
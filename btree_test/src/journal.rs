@@ -0,0 +1,265 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::nestedbtree;
+use crate::savefile::{self, ChunkTiles, Header, Record, SaveBackend, SaveChunks, SaveError};
+use crate::voxelstore::VoxelStore;
+
+// journal layout: magic, format version u16, backend id u16, then batches of the same chunk
+// records the save file uses, each batch closed by an end record. a chunk record holds the
+// whole chunk as it was when appended (no tiles = chunk is gone), so replaying a batch twice
+// gives the same map and a batch without its end record is a torn write that gets dropped
+pub const JOURNAL_MAGIC: [u8; 4] = *b"DDJN";
+const JOURNAL_HEADER_LEN: u64 = 8;
+
+// backends that remember which 16x16 chunks (same split as NestedBTree) changed since the last save
+pub trait DirtyChunks<T> {
+    fn dirty_chunks(&self) -> Vec<(u16, u16)>;
+    fn clear_dirty(&mut self);
+    // current tiles of one chunk in (column, z) order, empty once the chunk is gone
+    fn chunk_tiles(&self, chunk: (u16, u16)) -> ChunkTiles<'_, T>;
+}
+
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    // a rename only sticks once the directory entry is on disk, not every platform lets a
+    // directory be opened for that so this is unix only
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+// appends every dirty chunk to the journal as one batch and syncs it, returns how many chunks went out
+pub fn append<T, M>(map: &mut M, journal: &Path) -> Result<usize, SaveError>
+where
+    T: Serialize,
+    M: DirtyChunks<T> + SaveBackend,
+{
+    let dirty = map.dirty_chunks();
+    if dirty.is_empty() {
+        return Ok(0);
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(journal)?;
+    let mut batch: Vec<u8> = Vec::new();
+    if file.metadata()?.len() == 0 {
+        batch.extend_from_slice(&JOURNAL_MAGIC);
+        batch.extend_from_slice(&savefile::FORMAT_VERSION.to_le_bytes());
+        batch.extend_from_slice(&M::BACKEND_ID.to_le_bytes());
+    }
+    let mut tiles: u64 = 0;
+    for chunk in &dirty {
+        let entries = map.chunk_tiles(*chunk);
+        savefile::write_chunk(&mut batch, *chunk, &entries)?;
+        tiles += entries.len() as u64;
+    }
+    savefile::write_end(&mut batch, dirty.len() as u32, tiles)?;
+    // one write so a crash leaves at most one torn batch at the tail
+    file.write_all(&batch)?;
+    file.sync_data()?;
+    map.clear_dirty();
+    Ok(dirty.len())
+}
+
+// replays the journal onto map and returns how many batches were applied. a batch cut short by
+// the end of the file is cut off so later appends land after good data. a bad record anywhere
+// before that is an error and the file is left alone, map then holds the batches ahead of it
+pub fn replay<T, M>(map: &mut M, journal: &Path) -> Result<usize, SaveError>
+where
+    T: DeserializeOwned,
+    M: VoxelStore<T> + DirtyChunks<T> + SaveBackend,
+{
+    let mut bytes = Vec::new();
+    match File::open(journal) {
+        Ok(file) => BufReader::new(file).read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.len() < JOURNAL_HEADER_LEN as usize {
+        // never got its header out, nothing in it can have been committed
+        fs::remove_file(journal)?;
+        return Ok(0);
+    }
+    if bytes[0..4] != JOURNAL_MAGIC {
+        return Err(SaveError::BadMagic);
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version > savefile::FORMAT_VERSION {
+        return Err(SaveError::UnsupportedVersion(version));
+    }
+    let backend = u16::from_le_bytes([bytes[6], bytes[7]]);
+    if backend != M::BACKEND_ID {
+        return Err(SaveError::WrongBackend { expected: M::BACKEND_ID, found: backend });
+    }
+    let mut reader = &bytes[JOURNAL_HEADER_LEN as usize..];
+    let mut good = JOURNAL_HEADER_LEN;
+    let mut batches = 0;
+    let mut pending = Vec::new();
+    let mut tiles: u64 = 0;
+    while !reader.is_empty() {
+        let start = bytes.len() - reader.len();
+        match savefile::read_record::<T, _>(&mut reader) {
+            Ok(Record::Chunk { chunk, tiles: entries }) => {
+                // the journal doesn't know the map's size, only that its coordinates fit in a u16
+                savefile::check_chunk(chunk, [], (u32::MAX, u32::MAX, u32::MAX))?;
+                tiles += entries.len() as u64;
+                pending.push((chunk, entries));
+            }
+            Ok(Record::End { chunks, tiles: expected }) if chunks as usize == pending.len() && expected == tiles => {
+                for (chunk, entries) in pending.drain(..) {
+                    // the record is the whole chunk, so whatever the base had there goes first
+                    let stale: Vec<(u8, u8)> = map.chunk_tiles(chunk).iter().map(|t| (t.0, t.1)).collect();
+                    for (column, z) in stale {
+                        let (x, y) = nestedbtree::join(chunk, column);
+                        map.remove(x, y, z);
+                    }
+                    for (column, z, value) in entries {
+                        let (x, y) = nestedbtree::join(chunk, column);
+                        map.insert(x, y, z, value);
+                    }
+                }
+                tiles = 0;
+                batches += 1;
+                good = (bytes.len() - reader.len()) as u64;
+            }
+            Ok(Record::End { .. }) => {
+                return Err(SaveError::Corrupt(format!("batch ending at byte {} has the wrong chunk or tile count", start)));
+            }
+            Err(e) => {
+                // a crash half way through an append leaves a record that runs into the end of
+                // the file, one that ends before it was damaged some other way
+                if record_len(&bytes[start..]).is_some_and(|len| start + len < bytes.len()) {
+                    return Err(e);
+                }
+                break;
+            }
+        }
+    }
+    if good < bytes.len() as u64 {
+        OpenOptions::new().write(true).open(journal)?.set_len(good)?;
+    }
+    map.clear_dirty();
+    Ok(batches)
+}
+
+// length of the record at the start of rec going by its header, None when the header itself is cut off
+fn record_len(rec: &[u8]) -> Option<usize> {
+    match *rec.first()? {
+        savefile::TAG_END => Some(1 + 12 + 4),
+        savefile::TAG_CHUNK => {
+            let len = rec.get(9..13)?;
+            Some(1 + 12 + u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize + 4)
+        }
+        _ => Some(1),
+    }
+}
+
+// base save plus everything in the journal
+pub fn load<T, M>(base: &Path, journal: &Path) -> Result<(Header, M), SaveError>
+where
+    T: DeserializeOwned,
+    M: VoxelStore<T> + DirtyChunks<T> + SaveBackend + Default,
+{
+    let (header, mut map) = savefile::load(BufReader::new(File::open(base)?))?;
+    replay(&mut map, journal)?;
+    Ok((header, map))
+}
+
+// folds the journal into a fresh base file. dirty chunks go to the journal first so the journal
+// never holds anything older than the new base, then the base is written to a temp file, synced
+// and renamed over the old one. a crash at any point leaves either the old base and its journal
+// or the new base and a journal that replays to the same map
+pub fn compact<T, M>(map: &mut M, dims: (u32, u32, u32), base: &Path, journal: &Path) -> Result<(), SaveError>
+where
+    T: Serialize,
+    M: SaveChunks<T> + DirtyChunks<T> + SaveBackend,
+{
+    append(map, journal)?;
+    let mut tmp = base.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    savefile::save(map, dims, &mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp, base)?;
+    sync_parent(base)?;
+    match fs::remove_file(journal) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    sync_parent(journal)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nestedbtree::NestedBTree;
+
+    // three batches of one chunk each, returns the file and where each batch ends
+    fn three_batches(name: &str) -> (PathBuf, Vec<u64>) {
+        let path = std::env::temp_dir().join(format!("btree_test_{}_{}.dat", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut map: NestedBTree<u8> = NestedBTree::new();
+        let mut ends = Vec::new();
+        for i in 0..3u8 {
+            for z in 0..50 {
+                map.insert(i as u16 * 16, 5, z, i);
+            }
+            append(&mut map, &path).unwrap();
+            ends.push(fs::metadata(&path).unwrap().len());
+        }
+        (path, ends)
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let (path, ends) = three_batches("journal_torn");
+        let clean = fs::read(&path).unwrap();
+        for cut in [ends[1] + 1, ends[1] + 20, ends[2] - 1] {
+            fs::write(&path, &clean[..cut as usize]).unwrap();
+            let mut map: NestedBTree<u8> = NestedBTree::new();
+            assert_eq!(replay(&mut map, &path).unwrap(), 2);
+            assert_eq!(map.len(), 100);
+            assert_eq!(fs::metadata(&path).unwrap().len(), ends[1]);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damage_before_the_tail_is_an_error() {
+        let (path, ends) = three_batches("journal_damaged");
+        let clean = fs::read(&path).unwrap();
+        // a payload byte, the chunk's tile count and the end record's tile count of the middle batch
+        for at in [ends[0] + 20, ends[0] + 6, ends[1] - 8] {
+            let mut bytes = clean.clone();
+            bytes[at as usize] ^= 0x40;
+            fs::write(&path, &bytes).unwrap();
+            let mut map: NestedBTree<u8> = NestedBTree::new();
+            let result = replay(&mut map, &path);
+            assert!(matches!(result, Err(SaveError::Checksum { .. } | SaveError::Corrupt(_))), "byte {}: {:?}", at, result);
+            assert_eq!(fs::read(&path).unwrap(), bytes);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn chunks_past_u16_coordinates_are_rejected() {
+        let (path, ends) = three_batches("journal_far");
+        let mut bytes = fs::read(&path).unwrap()[..ends[0] as usize].to_vec();
+        savefile::write_chunk(&mut bytes, (4096, 0), &[(0, 0, &1u8)]).unwrap();
+        savefile::write_end(&mut bytes, 1, 1).unwrap();
+        fs::write(&path, &bytes).unwrap();
+        let mut map: NestedBTree<u8> = NestedBTree::new();
+        assert!(matches!(replay(&mut map, &path), Err(SaveError::Corrupt(_))));
+        fs::remove_file(&path).unwrap();
+    }
+}
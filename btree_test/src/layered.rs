@@ -0,0 +1,309 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::chunkedgrid::ChunkedGrid;
+use crate::journal::{self, DirtyChunks};
+use crate::nestedbtree::NestedBTree;
+use crate::savefile::{self, SaveError};
+use crate::voxelstore::VoxelStore;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layer {
+    Material,
+    Designation,
+    Fluid,
+    Light,
+}
+impl Layer {
+    pub const ALL: [Layer; 4] = [Layer::Material, Layer::Designation, Layer::Fluid, Layer::Light];
+    // also the layer's file name in a save directory
+    pub fn key(self) -> &'static str {
+        match self {
+            Layer::Material => "material",
+            Layer::Designation => "designation",
+            Layer::Fluid => "fluid",
+            Layer::Light => "light",
+        }
+    }
+}
+
+// one tile across every layer, None where a layer has nothing there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tile {
+    pub material: Option<u16>,
+    pub designation: Option<u8>,
+    pub fluid: Option<u8>,
+    pub light: Option<u8>,
+}
+
+// every tile component in its own map, so a system only touches the layer it cares about and
+// a layer that changes every tick doesn't drag the others into each save. layers that cover
+// most of the map are palette packed grids, the ones that are mostly empty are nested b-trees
+pub struct LayeredMap {
+    dims: (u32, u32, u32),
+    // stone, soil, walls and floors, set nearly everywhere below the surface
+    material: ChunkedGrid<u16>,
+    // dig, channel and build orders where a player marked them
+    designation: NestedBTree<u8>,
+    // water or magma depth 1..=7 where there's any
+    fluid: NestedBTree<u8>,
+    // light level of every lit tile, redone around light sources all the time
+    light: ChunkedGrid<u8>,
+    // directory whose files hold every layer as it was before the dirty chunks, None until the
+    // map is loaded from or saved to one as a whole
+    synced: Option<PathBuf>,
+}
+
+impl LayeredMap {
+    pub fn new(dims: (u32, u32, u32)) -> LayeredMap {
+        Self {
+            dims,
+            material: ChunkedGrid::new(),
+            designation: NestedBTree::new(),
+            fluid: NestedBTree::new(),
+            light: ChunkedGrid::new(),
+            synced: None,
+        }
+    }
+    pub fn dims(&self) -> (u32, u32, u32) {
+        self.dims
+    }
+    pub fn material(&self) -> &ChunkedGrid<u16> {
+        &self.material
+    }
+    pub fn material_mut(&mut self) -> &mut ChunkedGrid<u16> {
+        &mut self.material
+    }
+    pub fn designation(&self) -> &NestedBTree<u8> {
+        &self.designation
+    }
+    pub fn designation_mut(&mut self) -> &mut NestedBTree<u8> {
+        &mut self.designation
+    }
+    pub fn fluid(&self) -> &NestedBTree<u8> {
+        &self.fluid
+    }
+    pub fn fluid_mut(&mut self) -> &mut NestedBTree<u8> {
+        &mut self.fluid
+    }
+    pub fn light(&self) -> &ChunkedGrid<u8> {
+        &self.light
+    }
+    pub fn light_mut(&mut self) -> &mut ChunkedGrid<u8> {
+        &mut self.light
+    }
+    // one lookup per layer
+    pub fn tile(&self, x: u16, y: u16, z: u8) -> Tile {
+        Tile {
+            material: self.material.get(x, y, z).copied(),
+            designation: self.designation.get(x, y, z).copied(),
+            fluid: self.fluid.get(x, y, z).copied(),
+            light: self.light.get(x, y, z).copied(),
+        }
+    }
+    // every layer to what tile says, a None clears that layer. returns what was there
+    pub fn set_tile(&mut self, x: u16, y: u16, z: u8, tile: Tile) -> Tile {
+        fn set<T, M: VoxelStore<T>>(map: &mut M, x: u16, y: u16, z: u8, value: Option<T>) -> Option<T> {
+            match value {
+                Some(value) => map.insert(x, y, z, value),
+                None => map.remove(x, y, z),
+            }
+        }
+        Tile {
+            material: set(&mut self.material, x, y, z, tile.material),
+            designation: set(&mut self.designation, x, y, z, tile.designation),
+            fluid: set(&mut self.fluid, x, y, z, tile.fluid),
+            light: set(&mut self.light, x, y, z, tile.light),
+        }
+    }
+    pub fn remove_tile(&mut self, x: u16, y: u16, z: u8) -> Tile {
+        self.set_tile(x, y, z, Tile::default())
+    }
+    pub fn layer_len(&self, layer: Layer) -> usize {
+        match layer {
+            Layer::Material => self.material.len(),
+            Layer::Designation => self.designation.len(),
+            Layer::Fluid => self.fluid.len(),
+            Layer::Light => self.light.len(),
+        }
+    }
+    pub fn layer_memory(&self, layer: Layer) -> usize {
+        match layer {
+            Layer::Material => self.material.memory_usage(),
+            Layer::Designation => self.designation.memory_usage(),
+            Layer::Fluid => self.fluid.memory_usage(),
+            Layer::Light => self.light.memory_usage(),
+        }
+    }
+    pub fn memory_usage(&self) -> usize {
+        // every layer counts its own struct
+        std::mem::size_of::<(u32, u32, u32)>()
+            + Layer::ALL.iter().map(|&l| self.layer_memory(l)).sum::<usize>()
+    }
+    // layers written to since they were last saved or loaded
+    pub fn changed_layers(&self) -> Vec<Layer> {
+        Layer::ALL.into_iter()
+            .filter(|&layer| match layer {
+                Layer::Material => !self.material.dirty_chunks().is_empty(),
+                Layer::Designation => !self.designation.dirty_chunks().is_empty(),
+                Layer::Fluid => !self.fluid.dirty_chunks().is_empty(),
+                Layer::Light => !self.light.dirty_chunks().is_empty(),
+            })
+            .collect()
+    }
+    fn clear_dirty(&mut self, layer: Layer) {
+        match layer {
+            Layer::Material => self.material.clear_dirty(),
+            Layer::Designation => self.designation.clear_dirty(),
+            Layer::Fluid => self.fluid.clear_dirty(),
+            Layer::Light => self.light.clear_dirty(),
+        }
+    }
+    // one layer as an ordinary save file of its backend
+    pub fn save_layer<W: Write>(&self, layer: Layer, writer: W) -> Result<(), SaveError> {
+        match layer {
+            Layer::Material => savefile::save(&self.material, self.dims, writer),
+            Layer::Designation => savefile::save(&self.designation, self.dims, writer),
+            Layer::Fluid => savefile::save(&self.fluid, self.dims, writer),
+            Layer::Light => savefile::save(&self.light, self.dims, writer),
+        }
+    }
+    // replaces one layer with a save of it, the others are left alone. the next save_dir then
+    // writes every layer since the new one's file could be anywhere
+    pub fn load_layer<R: Read>(&mut self, layer: Layer, reader: R) -> Result<(), SaveError> {
+        self.synced = None;
+        match layer {
+            Layer::Material => {
+                let (header, map) = savefile::load(reader)?;
+                self.check_dims(layer, header.dims)?;
+                self.material = map;
+            }
+            Layer::Designation => {
+                let (header, map) = savefile::load(reader)?;
+                self.check_dims(layer, header.dims)?;
+                self.designation = map;
+            }
+            Layer::Fluid => {
+                let (header, map) = savefile::load(reader)?;
+                self.check_dims(layer, header.dims)?;
+                self.fluid = map;
+            }
+            Layer::Light => {
+                let (header, map) = savefile::load(reader)?;
+                self.check_dims(layer, header.dims)?;
+                self.light = map;
+            }
+        }
+        // loading inserts every tile, none of them are changes
+        self.clear_dirty(layer);
+        Ok(())
+    }
+    fn check_dims(&self, layer: Layer, dims: (u32, u32, u32)) -> Result<(), SaveError> {
+        if dims != self.dims {
+            return Err(SaveError::Corrupt(format!("{} layer is {:?}, the map is {:?}", layer.key(), dims, self.dims)));
+        }
+        Ok(())
+    }
+    // writes layers to <dir>/<layer>.dat, each through a temp file renamed over the old one so a
+    // crash leaves whole layers. only the changed ones when dir is where the map was last loaded
+    // from or saved to, every one without a file there and all of them anywhere else. returns
+    // the layers written
+    pub fn save_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<Vec<Layer>, SaveError> {
+        fs::create_dir_all(dir.as_ref())?;
+        let dir = fs::canonicalize(dir)?;
+        let synced = self.synced.take() == Some(dir.clone());
+        let changed = self.changed_layers();
+        let written: Vec<Layer> = Layer::ALL.into_iter()
+            .filter(|layer| !synced || changed.contains(layer) || !layer_path(&dir, *layer).exists())
+            .collect();
+        for &layer in &written {
+            let path = layer_path(&dir, layer);
+            let tmp = path.with_extension("dat.tmp");
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            self.save_layer(layer, &mut writer)?;
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+        }
+        if let Some(&layer) = written.first() {
+            journal::sync_parent(&layer_path(&dir, layer))?;
+        }
+        for &layer in &written {
+            self.clear_dirty(layer);
+        }
+        self.synced = Some(dir);
+        Ok(written)
+    }
+    // every layer save_dir wrote, a layer without a file starts out empty
+    pub fn load_dir<P: AsRef<Path>>(dir: P, dims: (u32, u32, u32)) -> Result<LayeredMap, SaveError> {
+        let mut map = LayeredMap::new(dims);
+        for layer in Layer::ALL {
+            match File::open(layer_path(dir.as_ref(), layer)) {
+                Ok(file) => map.load_layer(layer, BufReader::new(file))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        map.synced = fs::canonicalize(dir).ok();
+        Ok(map)
+    }
+}
+
+fn layer_path(dir: &Path, layer: Layer) -> PathBuf {
+    dir.join(layer.key()).with_extension("dat")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIMS: (u32, u32, u32) = (64, 64, 256);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("btree_test_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn tiles(map: &LayeredMap) -> Vec<Tile> {
+        (0..64).flat_map(|x| (0..64).map(move |y| (x, y))).map(|(x, y)| map.tile(x, y, 3)).collect()
+    }
+
+    #[test]
+    fn save_to_another_dir_writes_every_layer() {
+        let (a, b) = (temp_dir("layers_a"), temp_dir("layers_b"));
+        let mut map = LayeredMap::new(DIMS);
+        for i in 0..64 {
+            map.set_tile(i, 63 - i, 3, Tile { material: Some(i), designation: Some(1), fluid: Some(7), light: Some(15) });
+        }
+        assert_eq!(map.save_dir(&a).unwrap(), Layer::ALL);
+        let mut loaded = LayeredMap::load_dir(&a, DIMS).unwrap();
+        assert!(loaded.changed_layers().is_empty());
+        assert_eq!(loaded.save_dir(&a).unwrap(), []);
+        assert_eq!(loaded.save_dir(&b).unwrap(), Layer::ALL);
+        assert_eq!(tiles(&LayeredMap::load_dir(&b, DIMS).unwrap()), tiles(&map));
+        // back in a with only the fluid changed, light's file went missing meanwhile
+        loaded.fluid_mut().insert(5, 5, 3, 2);
+        fs::remove_file(layer_path(&a, Layer::Light)).unwrap();
+        assert_eq!(loaded.save_dir(&a).unwrap(), Layer::ALL);
+        fs::remove_file(layer_path(&a, Layer::Light)).unwrap();
+        loaded.fluid_mut().insert(6, 6, 3, 2);
+        assert_eq!(loaded.save_dir(&a).unwrap(), [Layer::Fluid, Layer::Light]);
+        assert_eq!(tiles(&LayeredMap::load_dir(&a, DIMS).unwrap()), tiles(&loaded));
+        fs::remove_dir_all(&a).unwrap();
+        fs::remove_dir_all(&b).unwrap();
+    }
+
+    #[test]
+    fn loading_a_single_layer_writes_every_layer_next() {
+        let dir = temp_dir("layers_single");
+        let mut map = LayeredMap::new(DIMS);
+        map.set_tile(1, 1, 3, Tile { material: Some(4), ..Tile::default() });
+        map.save_dir(&dir).unwrap();
+        let mut bytes = Vec::new();
+        map.save_layer(Layer::Material, &mut bytes).unwrap();
+        map.load_layer(Layer::Material, &bytes[..]).unwrap();
+        assert_eq!(map.save_dir(&dir).unwrap(), Layer::ALL);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
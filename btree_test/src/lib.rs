@@ -0,0 +1,17 @@
+pub mod nestedbtree;
+pub mod chunkedgrid;
+pub mod columnrle;
+pub mod octree;
+pub mod genericmap;
+pub mod slottedmap;
+pub mod voxelstore;
+pub mod savefile;
+pub mod codec;
+pub mod mapview;
+pub mod journal;
+pub mod compress;
+pub mod region;
+pub mod sharded;
+pub mod transaction;
+pub mod observed;
+pub mod layered;
@@ -1,554 +1,867 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self, File};
-use std::io;
-use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::Path;
 use std::time::{Duration, Instant};
-mod nestedbtree;
-mod genericmap;
-mod slottedmap;
-use crate::nestedbtree::DeeplyNestedBTree;
-use crate::nestedbtree::NestedBTree;
-use crate::genericmap::GenericMap;
-//use slotmap::{SlotMap, SecondaryMap, new_key_type};
-use rand::Rng;
-use slottedmap::SlottedMap;
+use btree_test::nestedbtree::DeeplyNestedBTree;
+use btree_test::nestedbtree::NestedBTree;
+use btree_test::chunkedgrid::ChunkedGrid;
+use btree_test::codec::{Archive, Bincode, Decode, Encode, LittleEndian, Postcard};
+use btree_test::columnrle::ColumnRle;
+use btree_test::compress::{self, Compression};
+use btree_test::genericmap::GenericMap;
+use btree_test::journal;
+use btree_test::layered::{Layer, LayeredMap, Tile};
+use btree_test::mapview::MapView;
+use btree_test::observed::{DirtyRegion, Observed};
+use btree_test::octree::Octree;
+use btree_test::region::RegionStore;
+use btree_test::savefile::{self, SaveBackend, SaveChunks};
+use btree_test::sharded::ShardedMap;
+use btree_test::slottedmap::SlottedMap;
+use btree_test::voxelstore::{Coord, VoxelStore};
+use mixed::{Direct, Mix, Workload};
+use pattern::{sweep_coords, AccessPattern};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use report::{BenchResult, Format};
+
+mod cli;
+mod memory;
+mod mixed;
+mod pattern;
+mod report;
+
+#[global_allocator]
+static ALLOCATOR: memory::Counting = memory::Counting;
+
+// what one timed run produced, the codec times are kept out of the read/write times
+#[derive(Default)]
+struct TestRun {
+    // insert, write, read and compare as far as the run got to them
+    ops: Vec<(&'static str, Duration)>,
+    elapsed: Duration,
+    compress: Duration,
+    decompress: Duration,
+    raw_size: u64,
+    // heap bytes over what was live before the map was made: the most at once during the run,
+    // what's still held at the end, and what the map's memory_usage makes of it
+    peak_heap: usize,
+    retained_heap: usize,
+    estimated_heap: usize,
+    // mixed workload only, ns per op for each of mixed::KINDS
+    latencies: [Vec<u32>; 4],
+}
 
 #[inline(always)]
-fn slot_map_test(width: u16, iterations: u32, serialize: bool) -> Duration {
-    //let mut map: SlotMap<(u16, u16, u8), u8> = SlotMap::with_capacity_and_key((iterations + 1) as usize);
-    let mut map: SlottedMap<(u16, u16, u8), u8> = SlottedMap::with_capacity(iterations as usize);
-    let mut coords: Vec<(u16, u16, u8)> = vec![];
-    let mut x: u16 = 0;
-    let mut y: u16 = 0;
-    let mut z: u8 = 0;
-    //let mut rng = rand::thread_rng();
-    for i in 0..iterations {
-        // x = rng.gen_range(0..width) as u16;
-        // y = rng.gen_range(0..width) as u16;
-        // z = rng.gen_range(0..255);
-        if x >= width {
-            x = 0;
-            y += 1;
-        }
-        if z == 255 {
-            z = 0;
-        }
-        coords.push((x, y, z));
-        x += 1;
-        z += 1;
-    }
+fn map_test<M>(name: &str, mut map: M, coords: &[Coord], test: &Test) -> TestRun
+where
+    M: VoxelStore<u8> + SaveChunks<u8> + SaveBackend + Default,
+{
+    let (width, iterations, serialize, compression) = (test.width, test.iterations, test.serialize, test.compression);
+    let mut run = TestRun::default();
     let start = Instant::now();
-    for x in 0..coords.len() {
-        let c = coords.get(x).unwrap();
-        map.insert((c.0, c.1, c.2), c.2);
+    for &(x, y, z) in coords {
+        map.insert(x, y, z, z);
     }
     let mut duration = start.elapsed();
-    println!("\nslot_map insert finished...{:?} elapsed", duration);
+    run.ops.push(("insert", duration));
+    println!("\n{} insert finished...{:?} elapsed", name, duration);
     if serialize {
         let start2 = Instant::now();
-        let mut data = bincode::serialize(&map).unwrap();
-        let mut file = File::create("test.dat").unwrap();
-        file.write_all(&data).unwrap();
-        duration = start2.elapsed();
-        println!("slot_map write finished...{:?} elapsed", duration);
-        let start2 = Instant::now();
-        let mut file2 = File::open("test.dat").unwrap();
-        let mut data2: Vec<u8> = vec![];
-        file2.read_to_end(&mut data2).unwrap();
-        let mut map2: SlottedMap<(u16, u16, u8), u8> = SlottedMap::with_capacity(iterations as usize);
-        map2 = bincode::deserialize(&data2).unwrap();
-        duration = start2.elapsed();
-        println!("slot_map read finished...{:?} elapsed", duration);
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-            for c in coords {
-                if map2.get(&c).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
-                }
-            }
-        duration = start2.elapsed();
-        println!("slot_map compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
-    } else {
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
+        let mut raw: Vec<u8> = Vec::new();
+        savefile::save(&map, (width as u32, width as u32, 256), &mut raw).unwrap();
+        let start3 = Instant::now();
+        let bytes = compress::encode(compression, &raw).unwrap();
+        run.compress = start3.elapsed();
+        fs::write("test.dat", &bytes).unwrap();
+        duration = start2.elapsed() - run.compress;
+        run.raw_size = raw.len() as u64;
+        run.ops.push(("write", duration));
+        println!("{} write finished...{:?} elapsed", name, duration);
+        if compression != Compression::None {
+            println!("{} {} compress finished...{:?} elapsed, {} -> {} bytes", name, compression.name(), run.compress, raw.len(), bytes.len());
+        }
         let start2 = Instant::now();
-        
-            for c in coords {
-                if map.get(&c).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
-                }
-            }
-        duration = start2.elapsed();
-        println!("slot_map compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
+        let bytes = fs::read("test.dat").unwrap();
+        let start3 = Instant::now();
+        let raw = compress::decode(&bytes).unwrap();
+        run.decompress = start3.elapsed();
+        map = savefile::load(&raw[..]).unwrap().1;
+        duration = start2.elapsed() - run.decompress;
+        run.ops.push(("read", duration));
+        println!("{} read finished...{:?} elapsed", name, duration);
+        if compression != Compression::None {
+            println!("{} {} decompress finished...{:?} elapsed", name, compression.name(), run.decompress);
+        }
     }
-    let elapsed = start.elapsed();
-    println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",elapsed,iterations,width,width,256,serialize);
-    elapsed
+    assert!(coords.len() == iterations as usize);
+    let start2 = Instant::now();
+    let same = coords.iter().all(|&(x, y, z)| map.get(x, y, z) == Some(&z));
+    duration = start2.elapsed();
+    run.ops.push(("compare", duration));
+    println!("{} compare finished...{:?} elapsed", name, duration);
+    println!("Data is the same = {}", same);
+    assert!(same);
+    run.elapsed = start.elapsed();
+    run.retained_heap = memory::allocated();
+    run.estimated_heap = map.memory_usage();
+    println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",run.elapsed,iterations,width,width,256,serialize);
+    run
 }
 
-//really frickin slow
-#[inline(always)]
-fn generic_map_test(width: u16, iterations: u32, serialize: bool) -> Duration {
-    let mut map: GenericMap<(u16, u16, u8), u8> = GenericMap::with_capacity((iterations + 1) as usize);
-    let mut coords: Vec<(u16, u16, u8)> = vec![];
-    let mut x: u16 = 0;
-    let mut y: u16 = 0;
-    let mut z: u8 = 0;
-    //let mut rng = rand::thread_rng();
-    for i in 0..iterations {
-        // x = rng.gen_range(0..width) as u16;
-        // y = rng.gen_range(0..width) as u16;
-        // z = rng.gen_range(0..255);
-        if x >= width {
-            x = 0;
-            y += 1;
-        }
-        if z == 255 {
-            z = 0;
-        }
-        coords.push((x, y, z));
-        x += 1;
-        z += 1;
+const REGION_DIR: &str = "test_regions";
+// well under what the sweep needs so chunks get evicted and paged back in
+const REGION_BUDGET: usize = 4 << 20;
+
+// region files aren't compressed, only the timings are filled in
+fn region_test(name: &str, coords: &[Coord], test: &Test) -> TestRun {
+    let (width, iterations, serialize) = (test.width, test.iterations, test.serialize);
+    if Path::new(REGION_DIR).exists() {
+        fs::remove_dir_all(REGION_DIR).unwrap();
     }
+    let mut map: RegionStore<u8> = RegionStore::open(REGION_DIR, REGION_BUDGET).unwrap();
+    let mut ops = Vec::new();
     let start = Instant::now();
-    for x in 0..coords.len() {
-        let c = coords.get(x).unwrap();
-        map.insert((c.0, c.1, c.2), c.2);
+    for &(x, y, z) in coords {
+        map.insert(x, y, z, z).unwrap();
     }
     let mut duration = start.elapsed();
-    println!("\ngeneric_map insert finished...{:?} elapsed", duration);
+    ops.push(("insert", duration));
+    println!("\n{} insert finished...{:?} elapsed", name, duration);
     if serialize {
         let start2 = Instant::now();
-        let mut data = bincode::serialize(&map).unwrap();
-        let mut file = File::create("test.dat").unwrap();
-        file.write_all(&data).unwrap();
-        duration = start2.elapsed();
-        println!("generic_map write finished...{:?} elapsed", duration);
-        let start2 = Instant::now();
-        let mut file2 = File::open("test.dat").unwrap();
-        let mut data2: Vec<u8> = vec![];
-        file2.read_to_end(&mut data2).unwrap();
-        let mut map2: GenericMap<(u16, u16, u8), u8> = GenericMap::with_capacity((iterations + 1) as usize);
-        map2 = bincode::deserialize(&data2).unwrap();
-        duration = start2.elapsed();
-        println!("generic_map read finished...{:?} elapsed", duration);
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-            for c in coords {
-                if map2.get(&c).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
-                }
-            }
-        duration = start2.elapsed();
-        println!("generic_map compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
-    } else {
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        
-            for c in coords {
-                if map.get(&c).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
-                }
-            }
+        map.flush().unwrap();
         duration = start2.elapsed();
-        println!("generic_map compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
+        ops.push(("write", duration));
+        println!("{} write finished...{:?} elapsed", name, duration);
+        // nothing is read up front, the compare below pages every chunk back in
+        drop(map);
+        map = RegionStore::open(REGION_DIR, REGION_BUDGET).unwrap();
     }
+    let start2 = Instant::now();
+    let same = coords.iter().all(|&(x, y, z)| map.get(x, y, z).unwrap() == Some(&z));
+    duration = start2.elapsed();
+    ops.push(("compare", duration));
+    println!("{} compare finished...{:?} elapsed, {} chunks loaded, {} bytes", name, duration, map.loaded_chunks(), map.memory_used());
+    println!("Data is the same = {}", same);
+    assert!(same);
+    map.flush().unwrap();
     let elapsed = start.elapsed();
     println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",elapsed,iterations,width,width,256,serialize);
-    elapsed
+    TestRun {
+        ops,
+        elapsed,
+        retained_heap: memory::allocated(),
+        estimated_heap: map.memory_used(),
+        ..TestRun::default()
+    }
 }
 
-#[inline(always)]
-fn nested_btree_test(width: u16, iterations: u32, serialize: bool) -> Duration {
-    let mut map: NestedBTree<u8> = NestedBTree::new();
-    let mut coords: Vec<(u16, u16, u8)> = vec![];
-    let mut x: u16 = 0;
-    let mut y = 0;
-    let mut z: u8 = 0;
-    //let mut rng = rand::thread_rng();
-    for i in 0..iterations {
-        // x = rng.gen_range(0..width);
-        // y = rng.gen_range(0..width);
-        // z = rng.gen_range(0..255);
-        if x >= width {
-            x = 0;
-            y += 1;
-        }
-        if z == 255 {
-            z = 0;
-        }
-        coords.push((x, y, z));
-        x += 1;
-        z += 1;
-    }
+// prefills the map from the access pattern, then times the mixed op stream against it
+fn mixed_test<M: mixed::Target>(name: &str, mut map: M, workload: &Workload, mut latencies: [Vec<u32>; 4]) -> TestRun {
+    let mut run = TestRun::default();
     let start = Instant::now();
-    for x in 0..coords.len() {
-        let c = coords.get(x).unwrap();
-        map.insert(c.0, c.1, c.2, c.2);
+    for &c in &workload.prefill {
+        map.insert(c, c.2);
     }
     let mut duration = start.elapsed();
-    println!("\nnested insert finished...{:?} elapsed", duration);
-    if serialize {
-        let start2 = Instant::now();
-        let mut data: Vec<u8> = vec![];
-        data = bincode::serialize(&map).unwrap();
-        let mut file = File::create("test.dat").unwrap();
-        file.write_all(&data).unwrap();
-        duration = start2.elapsed();
-        println!("nested write finished...{:?} elapsed", duration);
-        let start2 = Instant::now();
-        let mut file2 = File::open("test.dat").unwrap();
-        let mut data2: Vec<u8> = vec![];
-        file2.read_to_end(&mut data2).unwrap();
-        let mut map2: NestedBTree<u8> = NestedBTree::new();
-        map2 = bincode::deserialize(&data2).unwrap();
-        duration = start2.elapsed();
-        println!("nested read finished...{:?} elapsed", duration);
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        for c in coords {
-            if *map2.get(c.0, c.1, c.2).unwrap() != c.2 {
-                same = false;
-                panic!();
-            }
-        }
-        duration = start2.elapsed();
-        println!("nested compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
+    run.ops.push(("prefill", duration));
+    println!("\n{} prefill finished...{:?} elapsed", name, duration);
+    duration = mixed::run(&mut map, workload, &mut latencies);
+    run.ops.push(("mixed", duration));
+    println!(
+        "{} {} mixed ops finished...{:?} elapsed, {:.2} Mops/s",
+        name, workload.ops.len(), duration, workload.ops.len() as f64 / duration.as_secs_f64() / 1e6
+    );
+    let start2 = Instant::now();
+    let same = mixed::check(&mut map, workload);
+    println!("{} compare finished...{:?} elapsed", name, start2.elapsed());
+    println!("Data is the same = {}", same);
+    assert!(same);
+    run.elapsed = start.elapsed();
+    run.retained_heap = memory::allocated();
+    run.estimated_heap = map.memory_usage();
+    run.latencies = latencies;
+    run
+}
+
+// bytes the last test left on disk, removes it afterwards
+fn take_saved_size() -> u64 {
+    if Path::new(REGION_DIR).is_dir() {
+        let size = fs::read_dir(REGION_DIR).unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum();
+        fs::remove_dir_all(REGION_DIR).unwrap();
+        size
     } else {
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        for c in coords {
-            if *map.get(c.0, c.1, c.2).unwrap() != c.2 {
-                same = false;
-                panic!();
-            }
-        }
-        duration = start2.elapsed();
-        println!("nested compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
+        let size = File::metadata(&File::open("test.dat").unwrap()).unwrap().len();
+        fs::remove_file("test.dat").unwrap();
+        size
     }
-    let elapsed = start.elapsed();
-    println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",elapsed,iterations,width,width,256,serialize);
-    elapsed
 }
 
-#[inline(always)]
-fn deeply_nested_btree_test(width: u16, iterations: u32, serialize: bool) -> Duration {
-    let mut map: DeeplyNestedBTree<u8> = DeeplyNestedBTree::new();
-    let mut coords: Vec<(u16, u16, u8)> = vec![];
-    let mut x: u16 = 0;
-    let mut y: u16 = 0;
-    let mut z: u8 = 0;
-    //let mut rng = rand::thread_rng();
-    for i in 0..iterations {
-        // x = rng.gen_range(0..width);
-        // y = rng.gen_range(0..width);
-        // z = rng.gen_range(0..255);
-        if x >= width {
-            x = 0;
-            y += 1;
-        }
-        if z == 255 {
-            z = 0;
-        }
-        coords.push((x, y, z));
-        x += 1;
-        z += 1;
+// full save, then a handful of edits that should only cost their chunks in the journal
+fn journal_test(width: u16, iterations: u32, seed: u64) {
+    let (base, log) = (Path::new("test_base.dat"), Path::new("test_journal.dat"));
+    let coords = sweep_coords(width, iterations);
+    let mut map: NestedBTree<u8> = NestedBTree::new();
+    for &(x, y, z) in &coords {
+        map.insert(x, y, z, z);
     }
+    let dims = (width as u32, width as u32, 256);
     let start = Instant::now();
-    for x in 0..coords.len() {
-        let c = coords.get(x).unwrap();
-        DeeplyNestedBTree::insert(&mut map, c.0, c.1, c.2, c.2);
+    journal::compact(&mut map, dims, base, log).unwrap();
+    println!("\njournal base write finished...{:?} elapsed, {} bytes", start.elapsed(), fs::metadata(base).unwrap().len());
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..100 {
+        let (x, y, z) = coords[rng.gen_range(0..coords.len())];
+        map.insert(x, y, z, z.wrapping_add(1));
     }
-    let mut duration = start.elapsed();
-    println!("\ndeeply nested insert finished...{:?} elapsed", duration);
-    if serialize {
-        let start2 = Instant::now();
-        let mut data: Vec<u8> = vec![];
-        data = bincode::serialize(&map).unwrap();
-        let mut file = File::create("test.dat").unwrap();
-        file.write_all(&data).unwrap();
-        duration = start2.elapsed();
-        println!("deeply nested write finished...{:?} elapsed", duration);
-        let start2 = Instant::now();
-        let mut file2 = File::open("test.dat").unwrap();
-        let mut data2: Vec<u8> = vec![];
-        file2.read_to_end(&mut data2).unwrap();
-        let mut map2: DeeplyNestedBTree<u8> = DeeplyNestedBTree::new();
-        map2 = bincode::deserialize(&data2).unwrap();
-        duration = start2.elapsed();
-        println!("deeply nested read finished...{:?} elapsed", duration);
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        for c in coords {
-            if *DeeplyNestedBTree::get(&map2, c.0, c.1, c.2).unwrap() != c.2 {
-                same = false;
-                panic!();
-            }
-        }
-        duration = start2.elapsed();
-        println!("deeply nested compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
-    } else {
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        for c in coords {
-            if *DeeplyNestedBTree::get(&map, c.0, c.1, c.2).unwrap() != c.2 {
-                same = false;
-                panic!();
-            }
-        }
-        duration = start2.elapsed();
-        println!("deeply nested compare finished...{:?} elapsed", duration);
-        println!("Data is the same = {}", same);
+    map.remove(coords[0].0, coords[0].1, coords[0].2);
+    let start = Instant::now();
+    let chunks = journal::append(&mut map, log).unwrap();
+    println!("journal append finished...{:?} elapsed, {} chunks, {} bytes", start.elapsed(), chunks, fs::metadata(log).unwrap().len());
+    let start = Instant::now();
+    let loaded: NestedBTree<u8> = journal::load(base, log).unwrap().1;
+    println!("journal load finished...{:?} elapsed", start.elapsed());
+    let same = loaded.len() == map.len() && loaded.iter().eq(map.iter());
+    println!("Data is the same = {}", same);
+    assert!(same);
+    journal::compact(&mut map, dims, base, log).unwrap();
+    assert!(!log.exists());
+    fs::remove_file(base).unwrap();
+}
+
+// one save/load round trip of map through codec C
+fn codec_run<C: Encode<u8> + Decode<u8>>(map: &NestedBTree<u8>, dims: (u32, u32, u32)) -> String {
+    let start = Instant::now();
+    let mut bytes: Vec<u8> = Vec::new();
+    savefile::save_with::<C, _, _, _>(map, dims, &mut bytes).unwrap();
+    let write = start.elapsed();
+    let start = Instant::now();
+    let loaded: NestedBTree<u8> = savefile::load_with::<C, _, _, _>(&bytes[..]).unwrap().1;
+    let read = start.elapsed();
+    let same = loaded.len() == map.len() && loaded.iter().eq(map.iter());
+    println!("{} codec write finished...{:?} elapsed, read finished...{:?} elapsed, data is the same = {}", C::NAME, write, read, same);
+    assert!(same);
+    format!("{} codec: write time = {:?}, read time = {:?}, size = {:.2} MiB", C::NAME, write, read, bytes.len() as f64 / (1024.0 * 1024.0))
+}
+
+// same nested b-tree through every chunk codec
+fn codec_test(width: u16, iterations: u32) -> Vec<String> {
+    let coords = sweep_coords(width, iterations);
+    let mut map: NestedBTree<u8> = NestedBTree::new();
+    for &(x, y, z) in &coords {
+        map.insert(x, y, z, z);
     }
-    let elapsed = start.elapsed();
-    println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",elapsed,iterations,width,width,256,serialize);
-    elapsed
+    let dims = (width as u32, width as u32, 256);
+    println!();
+    let mut results = vec![format!("Results for chunk codecs: iterations = {}, width = {}", iterations, width)];
+    results.push(codec_run::<Bincode>(&map, dims));
+    results.push(codec_run::<Postcard>(&map, dims));
+    results.push(codec_run::<LittleEndian>(&map, dims));
+    results.push(codec_run::<Archive>(&map, dims));
+    results.push(mapview_run(&map, dims, &coords));
+    results
 }
 
-#[inline(always)]
-fn btree_test(width: u16, iterations: u32, zxy: bool, serialize: bool) -> Duration {
-    let mut mapz: BTreeMap<(u8, u16, u16), u8> = BTreeMap::new();
-    let mut mapx: BTreeMap<(u16, u16, u8), u8> = BTreeMap::new();
-    let mut coords: Vec<(u16, u16, u8)> = vec![];
-    let mut x: u16 = 0;
-    let mut y: u16 = 0;
-    let mut z: u8 = 0;
-    //let mut rng = rand::thread_rng();
-    for i in 0..iterations {
-        // x = rng.gen_range(0..width) as u16;
-        // y = rng.gen_range(0..width) as u16;
-        // z = rng.gen_range(0..255);
-        if x >= width {
-            x = 0;
-            y += 1;
-        }
-        if z == 255 {
-            z = 0;
-        }
-        coords.push((x, y, z));
-        x += 1;
-        z += 1;
+// archive save opened as a memory mapped view instead of being loaded
+fn mapview_run(map: &NestedBTree<u8>, dims: (u32, u32, u32), coords: &[Coord]) -> String {
+    let file = BufWriter::new(File::create("test_view.dat").unwrap());
+    savefile::save_with::<Archive, _, _, _>(map, dims, file).unwrap();
+    let start = Instant::now();
+    let view: MapView<u8> = MapView::open("test_view.dat").unwrap();
+    let open = start.elapsed();
+    let start = Instant::now();
+    let same = coords.iter().all(|&(x, y, z)| view.get(x, y, z) == Some(z)) && view.len() == map.len();
+    let get = start.elapsed();
+    println!("mapped view open finished...{:?} elapsed, compare finished...{:?} elapsed, data is the same = {}", open, get, same);
+    assert!(same);
+    view.verify().unwrap();
+    drop(view);
+    fs::remove_file("test_view.dat").unwrap();
+    format!("mapped archive view: open time = {:?}, get every tile = {:?}", open, get)
+}
+
+// random tiles over the whole map with random values, the last write to a tile is what it should hold
+fn nested_btree_random_test(iterations: u32, seed: u64) {
+    let mut coords: Vec<(u16,u16,u8,u32)> = Vec::new();
+    let mut map: NestedBTree<u32> = NestedBTree::new();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        let x = rng.gen_range(0..64000);
+        let y = rng.gen_range(0..64000);
+        let z = rng.gen_range(0..255);
+        let val = rng.gen_range(0..u32::MAX);
+        coords.push((x,y,z,val));
+        map.insert(x, y, z, val);
+    }
+    println!("\nnested btree random insert finished...{:?} elapsed", start.elapsed());
+    let expected: BTreeMap<Coord, u32> = coords.iter().map(|c| ((c.0, c.1, c.2), c.3)).collect();
+    let same = map.len() == expected.len() && expected.iter().all(|(c, v)| map.get(c.0, c.1, c.2) == Some(v));
+    println!("nested btree random test: {} inserts, {} tiles, data matches = {}", iterations, map.len(), same);
+    assert!(same);
+}
+// saves a snapshot on another thread while the live map keeps changing, against a save that
+// blocks for the whole thing
+fn snapshot_test(width: u16, iterations: u32, seed: u64) -> Vec<String> {
+    let coords = sweep_coords(width, iterations);
+    let mut map: NestedBTree<u8> = NestedBTree::new();
+    for &(x, y, z) in &coords {
+        map.insert(x, y, z, z);
     }
+    let dims = (width as u32, width as u32, 256);
     let start = Instant::now();
-    for x in 0..coords.len() {
-        let c = coords.get(x).unwrap();
-        if zxy {
-            mapz.insert((c.2, c.0, c.1), c.2);
-        } else {
-            mapx.insert((c.0, c.1, c.2), c.2);
-        }
+    let mut blocking: Vec<u8> = Vec::new();
+    savefile::save(&map, dims, &mut blocking).unwrap();
+    let blocked = start.elapsed();
+
+    let start = Instant::now();
+    let snapshot = map.snapshot();
+    let taken = start.elapsed();
+    let saver = std::thread::spawn(move || {
+        let start = Instant::now();
+        let mut bytes: Vec<u8> = Vec::new();
+        savefile::save(&snapshot, dims, &mut bytes).unwrap();
+        (bytes, start.elapsed())
+    });
+    // the game carries on, every write lands in a chunk the snapshot still holds
+    let mut rng = StdRng::seed_from_u64(seed);
+    let start = Instant::now();
+    let edits = (iterations / 20).max(1);
+    for _ in 0..edits {
+        let (x, y, z) = coords[rng.gen_range(0..coords.len())];
+        map.insert(x, y, z, z.wrapping_add(1));
     }
-    let mut duration = start.elapsed();
-    if zxy {
-        println!("\nz,x,y insert finished...{:?} elapsed", duration);
-    } else {
-        println!("\nx,y,z insert finished...{:?} elapsed", duration);
+    let edited = start.elapsed();
+    let start = Instant::now();
+    let (bytes, saved) = saver.join().unwrap();
+    let waited = start.elapsed();
+    // the background save has to be the map from before the edits, byte for byte
+    let same = bytes == blocking;
+    let loaded: NestedBTree<u8> = savefile::load(&bytes[..]).unwrap().1;
+    let same = same && loaded.len() == coords.len() && coords.iter().all(|&(x, y, z)| loaded.get(x, y, z) == Some(&z));
+    println!(
+        "\nsnapshot taken...{:?} elapsed, background save finished...{:?} elapsed, {} edits meanwhile...{:?} elapsed, waited...{:?} for the save, data is the same = {}",
+        taken, saved, edits, edited, waited, same
+    );
+    assert!(same);
+    let snapshot = map.snapshot();
+    map.insert(coords[0].0, coords[0].1, coords[0].2, 0);
+    let shared = map.shared_chunks(&snapshot);
+    assert!(shared + 1 == map.chunks().count() && snapshot.get(coords[0].0, coords[0].1, coords[0].2) == Some(&coords[0].2));
+    vec![
+        format!("Results for snapshots: iterations = {}, width = {}", iterations, width),
+        format!(
+            "blocking save = {:?}, snapshot = {:?}, background save = {:?}, {} edits during it = {:?}",
+            blocked, taken, saved, edits, edited
+        ),
+    ]
+}
+
+// ticks of random edits around the fortresses, the queued events replayed on a flat b-tree have
+// to end up as the map, and the dirty regions have to cover every tile that changed
+fn observed_test(width: u16, iterations: u32, seed: u64) -> Vec<String> {
+    let coords = AccessPattern::Clustered.coords(width, iterations, seed);
+    let start = Instant::now();
+    let mut plain: NestedBTree<u8> = NestedBTree::new();
+    for &(x, y, z) in &coords {
+        plain.insert(x, y, z, z);
     }
-    if serialize {
-        let start2 = Instant::now();
-        let mut data: Vec<u8> = vec![];
-        if zxy {
-            data = bincode::serialize(&mapz).unwrap();
-        } else {
-            data = bincode::serialize(&mapx).unwrap();
-        }
-        let mut file = File::create("test.dat").unwrap();
-        file.write_all(&data).unwrap();
-        duration = start2.elapsed();
-        if zxy {
-            println!("z,x,y write finished...{:?} elapsed", duration);
-        } else {
-            println!("x,y,z write finished...{:?} elapsed", duration);
-        }
-        let start2 = Instant::now();
-        let mut file2 = File::open("test.dat").unwrap();
-        let mut data2: Vec<u8> = vec![];
-        file2.read_to_end(&mut data2).unwrap();
-        let mut map2z: BTreeMap<(u8, u16, u16), u8> = BTreeMap::new();
-        let mut map2x: BTreeMap<(u16, u16, u8), u8> = BTreeMap::new();
-        if zxy {
-            map2z = bincode::deserialize(&data2).unwrap();
-        } else {
-            map2x = bincode::deserialize(&data2).unwrap();
-        }
-        duration = start2.elapsed();
-        if zxy {
-            println!("z,x,y read finished...{:?} elapsed", duration);
-        } else {
-            println!("x,y,z read finished...{:?} elapsed", duration);
-        }
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        if zxy {
-            for c in coords {
-                if map2z.get(&(c.2, c.0, c.1)).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
+    let insert = start.elapsed();
+    let start = Instant::now();
+    let mut map: Observed<NestedBTree<u8>, u8> = Observed::with_queue(NestedBTree::new());
+    let heard = std::rc::Rc::new(std::cell::Cell::new(0usize));
+    let counter = heard.clone();
+    map.subscribe(move |_| counter.set(counter.get() + 1));
+    for &(x, y, z) in &coords {
+        map.insert(x, y, z, z);
+    }
+    let observed_insert = start.elapsed();
+    let mut replayed: BTreeMap<Coord, u8> = BTreeMap::new();
+    let mut same = true;
+    let mut ticks = 0;
+    let mut total = DirtyRegion::default();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let start = Instant::now();
+    for batch in coords.chunks(1000) {
+        let mut changed: BTreeSet<Coord> = BTreeSet::new();
+        for &(x, y, z) in batch {
+            match rng.gen_range(0..4) {
+                0 => {
+                    if map.remove(x, y, z).is_some() {
+                        changed.insert((x, y, z));
+                    }
                 }
-            }
-        } else {
-            for c in coords {
-                if map2x.get(&c).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
+                1 => {
+                    if let Some(v) = map.get_mut(x, y, z) {
+                        *v = v.wrapping_add(1);
+                        changed.insert((x, y, z));
+                    }
                 }
-            }
-        }
-        duration = start2.elapsed();
-        if zxy {
-            println!("z,x,y compare finished...{:?} elapsed", duration);
-        } else {
-            println!("x,y,z compare finished...{:?} elapsed", duration);
-        }
-        println!("Data is the same = {}", same);
-    } else {
-        let mut same: bool = true;
-        assert!(coords.len() == iterations as usize);
-        let start2 = Instant::now();
-        if zxy {
-            for c in coords {
-                if mapz.get(&(c.2, c.0, c.1)).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
+                // the same value again isn't a change
+                2 => {
+                    map.insert(x, y, z, z);
                 }
-            }
-        } else {
-            for c in coords {
-                if mapx.get(&c).unwrap() != &c.2 {
-                    same = false;
-                    panic!();
+                _ => {
+                    let v = rng.gen();
+                    if map.insert(x, y, z, v) != Some(v) {
+                        changed.insert((x, y, z));
+                    }
                 }
             }
         }
-        duration = start2.elapsed();
-        if zxy {
-            println!("z,x,y compare finished...{:?} elapsed", duration);
+        for change in map.drain_events() {
+            same &= match change.new {
+                Some(v) => replayed.insert(change.tile, v),
+                None => replayed.remove(&change.tile),
+            } == change.old;
+        }
+        let dirty = map.take_dirty();
+        same &= changed.iter().all(|&c| {
+            let chunk = btree_test::nestedbtree::split(c.0, c.1).0;
+            dirty.chunks.get(&chunk).is_some_and(|b| b.contains(c)) && dirty.bounds.is_some_and(|b| b.contains(c))
+        });
+        total.merge(dirty);
+        ticks += 1;
+    }
+    let edits = start.elapsed();
+    same &= replayed.len() == map.len() && map.iter().eq(replayed.iter().map(|(c, v)| (*c, v)));
+    // the first tick's events and dirty region hold the fill as well
+    same &= heard.get() == total.changes;
+    println!(
+        "\nobserved insert finished...{:?} elapsed, {} ticks of edits...{:?} elapsed, {} changes over {} chunks, data is the same = {}",
+        observed_insert, ticks, edits, total.changes, total.chunks.len(), same
+    );
+    assert!(same);
+    vec![
+        format!("Results for observed writes: iterations = {}, width = {}", iterations, width),
+        format!(
+            "nested btree insert = {:?}, observed insert with queue and callback = {:?}, {} ticks of edits = {:?}, {} changes in {} dirty chunks",
+            insert, observed_insert, ticks, edits, total.changes, total.chunks.len()
+        ),
+    ]
+}
+
+const LAYER_DIR: &str = "test_layers";
+
+// material under every swept tile with light, fluid and designations around the fortresses, saved a
+// layer per file. a tick's worth of lighting only rewrites the light layer
+fn layered_test(width: u16, iterations: u32, seed: u64) -> Vec<String> {
+    let dims = (width as u32, width as u32, 256);
+    let coords = sweep_coords(width, iterations);
+    let fortress = AccessPattern::Clustered.coords(width, iterations / 10, seed);
+    let mut map = LayeredMap::new(dims);
+    let start = Instant::now();
+    for &(x, y, z) in &coords {
+        map.material_mut().insert(x, y, z, z as u16 / 16);
+        map.light_mut().insert(x, y, z, 15 - z / 17);
+    }
+    for (i, &(x, y, z)) in fortress.iter().enumerate() {
+        if i % 4 == 0 {
+            map.designation_mut().insert(x, y, z, 1);
         } else {
-            println!("x,y,z compare finished...{:?} elapsed", duration);
+            map.fluid_mut().insert(x, y, z, (i % 7) as u8 + 1);
         }
-        println!("Data is the same = {}", same);
     }
-    let elapsed = start.elapsed();
-    if zxy {
-        println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",elapsed,iterations,256,width,width,serialize);
-    } else {
-        println!("total elapsed = {:?}, iterations = {}, dimenions = {} X {} X {}, serialize/deserialize = {}",elapsed,iterations,width,width,256,serialize);
+    let fill = start.elapsed();
+    // one layer read on its own against whole tiles
+    let start = Instant::now();
+    let lit = coords.iter().filter(|&&(x, y, z)| map.light().get(x, y, z).is_some_and(|l| *l > 7)).count();
+    let light_only = start.elapsed();
+    let start = Instant::now();
+    let lit_tiles = coords.iter().filter(|&&(x, y, z)| map.tile(x, y, z).light.is_some_and(|l| l > 7)).count();
+    let whole_tiles = start.elapsed();
+    let mut same = lit == lit_tiles;
+
+    if Path::new(LAYER_DIR).exists() {
+        fs::remove_dir_all(LAYER_DIR).unwrap();
+    }
+    let start = Instant::now();
+    let written = map.save_dir(LAYER_DIR).unwrap();
+    let full_save = start.elapsed();
+    same &= written == Layer::ALL;
+    // a torch moved, only the light layer changes
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..1000 {
+        let (x, y, z) = coords[rng.gen_range(0..coords.len())];
+        map.light_mut().insert(x, y, z, 15);
     }
-    elapsed
+    let start = Instant::now();
+    let written = map.save_dir(LAYER_DIR).unwrap();
+    let light_save = start.elapsed();
+    same &= written == [Layer::Light] && map.changed_layers().is_empty();
+    let start = Instant::now();
+    let loaded = LayeredMap::load_dir(LAYER_DIR, dims).unwrap();
+    let load = start.elapsed();
+    same &= Layer::ALL.iter().all(|&l| loaded.layer_len(l) == map.layer_len(l)) && loaded.changed_layers().is_empty();
+    same &= VoxelStore::iter(loaded.material()).eq(VoxelStore::iter(map.material()))
+        && VoxelStore::iter(loaded.light()).eq(VoxelStore::iter(map.light()))
+        && loaded.designation().iter().eq(map.designation().iter())
+        && loaded.fluid().iter().eq(map.fluid().iter());
+    // a dug out tile goes from every layer at once
+    let (x, y, z) = fortress[0];
+    let before = map.tile(x, y, z);
+    same &= map.remove_tile(x, y, z) == before && map.tile(x, y, z) == Tile::default();
+    println!(
+        "\nlayered fill finished...{:?} elapsed, light layer read...{:?} elapsed, whole tile read...{:?} elapsed",
+        fill, light_only, whole_tiles
+    );
+    println!(
+        "layered save of every layer...{:?} elapsed, light layer only...{:?} elapsed, load...{:?} elapsed, data is the same = {}",
+        full_save, light_save, load, same
+    );
+    assert!(same);
+    let sizes: Vec<String> = Layer::ALL.iter()
+        .map(|&l| {
+            let bytes = fs::metadata(Path::new(LAYER_DIR).join(l.key()).with_extension("dat")).unwrap().len();
+            format!("{} = {} tiles, {:.2} MiB saved, {:.2} MiB held", l.key(), map.layer_len(l), bytes as f64 / (1024.0 * 1024.0), map.layer_memory(l) as f64 / (1024.0 * 1024.0))
+        })
+        .collect();
+    fs::remove_dir_all(LAYER_DIR).unwrap();
+    vec![
+        format!("Results for layered map: iterations = {}, width = {}", iterations, width),
+        format!(
+            "light layer read = {:?}, whole tile read = {:?}, save every layer = {:?}, save light only = {:?}, load = {:?}",
+            light_only, whole_tiles, full_save, light_save, load
+        ),
+        sizes.join(", "),
+    ]
 }
 
-fn nested_btree_random_test(iterations: u32) {
-    let mut coords: Vec<(u16,u16,u8,u32)> = Vec::new();
-    let mut map: NestedBTree<u32> = NestedBTree::new();
-    let mut rng = rand::thread_rng();
-    for i in 0..iterations {
-        let x = rng.gen_range(0..64000);
-        let y = rng.gen_range(0..64000);
-        let z = rng.gen_range(0..255);
-        let val = rng.gen_range(0..u32::MAX);
-        coords.push((x,y,z,val));
-        map.insert(x, y, z, val);
+// fortress tiles filled in one at a time and as a parallel batch, then read back on one thread and
+// on the rayon pool
+fn parallel_test(width: u16, iterations: u32, seed: u64) -> Vec<String> {
+    let coords = AccessPattern::Clustered.coords(width, iterations, seed);
+    let tiles: Vec<(Coord, u8)> = coords.iter().map(|&c| (c, c.2)).collect();
+    println!("\nparallel test on {} threads", rayon::current_num_threads());
+    let mut results = vec![format!(
+        "Results for parallel access: iterations = {}, width = {}, threads = {}",
+        iterations, width, rayon::current_num_threads()
+    )];
+
+    let start = Instant::now();
+    let mut nested: NestedBTree<u8> = NestedBTree::new();
+    for &((x, y, z), v) in &tiles {
+        nested.insert(x, y, z, v);
     }
-    println!("insert done");
-    let mut same: bool = true;
-    for i in 0..coords.len() {
-        let c = coords.get(i).unwrap();
-        if !map.get(c.0, c.1, c.2).unwrap() == c.3 {
-            println!("data mismatch");
-            same = false;
-            break;
-        }
+    let insert = start.elapsed();
+    let batch = tiles.clone();
+    let start = Instant::now();
+    let mut par_nested: NestedBTree<u8> = NestedBTree::new();
+    par_nested.par_insert_batch(batch);
+    let par_insert = start.elapsed();
+    let start = Instant::now();
+    let sum: u64 = nested.iter().map(|(_, v)| *v as u64).sum();
+    let iter = start.elapsed();
+    let start = Instant::now();
+    let par_sum: u64 = par_nested.par_iter().map(|(_, v)| *v as u64).sum();
+    let par_iter = start.elapsed();
+    let same = par_nested.len() == nested.len() && par_nested.iter().eq(nested.iter()) && sum == par_sum;
+    println!("nested btree insert = {:?}, par_insert_batch = {:?}, iter = {:?}, par_iter = {:?}, data is the same = {}", insert, par_insert, iter, par_iter, same);
+    assert!(same);
+    results.push(format!("nested btree: insert = {:?}, par_insert_batch = {:?}, iter = {:?}, par_iter = {:?}", insert, par_insert, iter, par_iter));
+
+    let start = Instant::now();
+    let mut grid: ChunkedGrid<u8> = ChunkedGrid::new();
+    for &((x, y, z), v) in &tiles {
+        grid.insert(x, y, z, v);
     }
-    println!("data matches = {}", same);
-    println!("\nPress any key to continue...");
-    let mut stdin = io::stdin();
-    let _ = stdin.read(&mut [0u8]).unwrap();
+    let insert = start.elapsed();
+    let batch = tiles.clone();
+    let start = Instant::now();
+    let mut par_grid: ChunkedGrid<u8> = ChunkedGrid::new();
+    par_grid.par_insert_batch(batch);
+    let par_insert = start.elapsed();
+    let start = Instant::now();
+    let sum: u64 = VoxelStore::iter(&grid).map(|(_, v)| *v as u64).sum();
+    let iter = start.elapsed();
+    let start = Instant::now();
+    let par_sum: u64 = par_grid.par_iter().map(|(_, v)| *v as u64).sum();
+    let par_iter = start.elapsed();
+    let same = par_grid.len() == grid.len() && VoxelStore::iter(&par_grid).eq(VoxelStore::iter(&grid)) && sum == par_sum;
+    println!("chunked grid insert = {:?}, par_insert_batch = {:?}, iter = {:?}, par_iter = {:?}, data is the same = {}", insert, par_insert, iter, par_iter, same);
+    assert!(same);
+    results.push(format!("chunked grid: insert = {:?}, par_insert_batch = {:?}, iter = {:?}, par_iter = {:?}", insert, par_insert, iter, par_iter));
+
+    // the sharded map against the single threaded nested b-tree it's made of
+    let start = Instant::now();
+    let sharded: ShardedMap<u8> = ShardedMap::new();
+    sharded.par_insert_batch(tiles);
+    let par_insert = start.elapsed();
+    let start = Instant::now();
+    let sum: u64 = coords.iter().map(|&(x, y, z)| *nested.get(x, y, z).unwrap() as u64).sum();
+    let get = start.elapsed();
+    let start = Instant::now();
+    let par_sum: u64 = coords.par_iter().map(|&(x, y, z)| sharded.get(x, y, z).unwrap() as u64).sum();
+    let par_get = start.elapsed();
+    // readers and writers at once, every writer bumps its own tiles so the end state is known
+    let start = Instant::now();
+    coords.par_iter().enumerate().for_each(|(i, &(x, y, z))| {
+        if i % 4 == 0 {
+            sharded.update(x, y, z, |v| *v = z.wrapping_add(1));
+        } else {
+            std::hint::black_box(sharded.get(x, y, z));
+        }
+    });
+    let par_mixed = start.elapsed();
+    let bumped: BTreeSet<Coord> = coords.iter().step_by(4).copied().collect();
+    let same = sum == par_sum && sharded.len() == nested.len()
+        && coords.iter().all(|c| sharded.get(c.0, c.1, c.2) == Some(if bumped.contains(c) { c.2 + 1 } else { c.2 }));
+    println!("sharded map par_insert_batch = {:?}, get = {:?}, par get = {:?}, par 3:1 get/update = {:?}, data is the same = {}", par_insert, get, par_get, par_mixed, same);
+    assert!(same);
+    results.push(format!(
+        "sharded map: par_insert_batch = {:?}, nested btree get = {:?}, sharded par get = {:?}, sharded par 3:1 get/update = {:?}",
+        par_insert, get, par_get, par_mixed
+    ));
+    results
 }
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 enum TestType {
     FlatBtreeXYZ,
     FlatBtreeZXY,
     DeeplyNestedBTree,
+    ColumnRle,
     NestedBTree,
-    GenericMap, // really slow
-    SlotMap, // really slow
+    ChunkedGrid,
+    Octree,
+    GenericMap, // slow inserts, every one shifts the vectors
+    SlotMap,
+    RegionStore, // pages chunks in and out of region files under a memory budget
+}
+impl TestType {
+    const ALL: [TestType; 10] = [
+        TestType::SlotMap,
+        TestType::FlatBtreeXYZ,
+        TestType::FlatBtreeZXY,
+        TestType::DeeplyNestedBTree,
+        TestType::ColumnRle,
+        TestType::NestedBTree,
+        TestType::ChunkedGrid,
+        TestType::Octree,
+        TestType::GenericMap,
+        TestType::RegionStore,
+    ];
+    // name on the command line
+    fn key(self) -> &'static str {
+        match self {
+            TestType::DeeplyNestedBTree => "deep",
+            TestType::ColumnRle => "rle",
+            TestType::NestedBTree => "nested",
+            TestType::ChunkedGrid => "grid",
+            TestType::Octree => "octree",
+            TestType::FlatBtreeXYZ => "xyz",
+            TestType::FlatBtreeZXY => "zxy",
+            TestType::GenericMap => "generic",
+            TestType::SlotMap => "slot",
+            TestType::RegionStore => "region",
+        }
+    }
+    fn label(self) -> &'static str {
+        match self {
+            TestType::DeeplyNestedBTree => "deeply nested",
+            TestType::ColumnRle => "column rle",
+            TestType::NestedBTree => "nested",
+            TestType::ChunkedGrid => "chunked grid",
+            TestType::Octree => "octree",
+            TestType::FlatBtreeXYZ => "x,y,z",
+            TestType::FlatBtreeZXY => "z,x,y",
+            TestType::GenericMap => "generic_map",
+            TestType::SlotMap => "slot_map",
+            TestType::RegionStore => "region store",
+        }
+    }
+    fn name(self) -> &'static str {
+        match self {
+            TestType::DeeplyNestedBTree => "Results for deeply nested btree",
+            TestType::ColumnRle => "Results for column rle",
+            TestType::NestedBTree => "Results for nested btree",
+            TestType::ChunkedGrid => "Results for chunked grid",
+            TestType::Octree => "Results for octree",
+            TestType::FlatBtreeXYZ => "Results for flat b-tree as XYZ",
+            TestType::FlatBtreeZXY => "Results for flat b-tree as ZXY",
+            TestType::GenericMap => "Results for generic_map",
+            TestType::SlotMap => "Results for slot map",
+            TestType::RegionStore => "Results for region store",
+        }
+    }
 }
 struct Test {
     pub num_tests: u32,
     pub iterations: u32,
     pub width: u16,
     pub serialize: bool,
+    pub compression: Compression,
+    pub pattern: AccessPattern,
+    pub seed: u64,
+    // mixed workload instead of bulk insert then compare
+    pub mix: Option<Mix>,
     pub test_type: TestType,
 }
 impl Test {
-    fn new(
-        num_tests: u32,
-        iterations: u32,
-        width: u16,
-        serialize: bool,
-        test_type: TestType,
-    ) -> Test {
+    fn new(options: &cli::Options, test_type: TestType) -> Test {
         Self {
-            num_tests: num_tests,
-            iterations: iterations,
-            width: width,
-            serialize: serialize,
-            test_type: test_type,
+            num_tests: options.repeat,
+            iterations: options.iterations,
+            width: options.width,
+            // the mixed workload never saves
+            serialize: options.serialize && options.mix.is_none(),
+            compression: options.compression,
+            pattern: options.pattern,
+            seed: options.seed,
+            mix: options.mix,
+            test_type,
+        }
+    }
+    fn run(&self) -> TestRun {
+        let label = self.test_type.label();
+        let coords = &self.pattern.coords(self.width, self.iterations, self.seed);
+        // seeded apart from the pattern so random inserts don't just land on prefilled tiles again
+        let workload = self.mix.map(|mix| Workload::new(coords, self.iterations as usize, mix, self.width, self.seed.wrapping_add(1)));
+        let latencies = workload.as_ref().map(Workload::buffers).unwrap_or_default();
+        // anything allocated past here belongs to the map or its save
+        let heap = memory::allocated();
+        memory::reset_peak();
+        let mut run = if let Some(workload) = &workload {
+            self.run_mixed(label, workload, latencies)
+        } else {
+            self.run_bulk(label, coords)
+        };
+        run.peak_heap = memory::peak() - heap;
+        run.retained_heap = run.retained_heap.saturating_sub(heap);
+        println!("heap peak = {} bytes, retained = {} bytes, estimated = {} bytes", run.peak_heap, run.retained_heap, run.estimated_heap);
+        run
+    }
+    fn run_bulk(&self, label: &str, coords: &[Coord]) -> TestRun {
+        match self.test_type {
+            TestType::DeeplyNestedBTree => map_test(label, DeeplyNestedBTree::<u8>::new(), coords, self),
+            TestType::ColumnRle => map_test(label, ColumnRle::<u8>::new(), coords, self),
+            TestType::NestedBTree => map_test(label, NestedBTree::<u8>::new(), coords, self),
+            TestType::ChunkedGrid => map_test(label, ChunkedGrid::<u8>::new(), coords, self),
+            TestType::Octree => map_test(label, Octree::<u8>::new(), coords, self),
+            TestType::FlatBtreeXYZ => map_test(label, BTreeMap::<(u16, u16, u8), u8>::new(), coords, self),
+            TestType::FlatBtreeZXY => map_test(label, BTreeMap::<(u8, u16, u16), u8>::new(), coords, self),
+            TestType::GenericMap => {
+                let map: GenericMap<Coord, u8> = GenericMap::with_capacity((self.iterations + 1) as usize);
+                map_test(label, map, coords, self)
+            }
+            TestType::SlotMap => {
+                let map: SlottedMap<Coord, u8> = SlottedMap::with_capacity(self.iterations as usize);
+                map_test(label, map, coords, self)
+            }
+            TestType::RegionStore => region_test(label, coords, self),
+        }
+    }
+    fn run_mixed(&self, label: &str, workload: &Workload, latencies: [Vec<u32>; 4]) -> TestRun {
+        match self.test_type {
+            TestType::DeeplyNestedBTree => mixed_test(label, Direct(DeeplyNestedBTree::<u8>::new()), workload, latencies),
+            TestType::ColumnRle => mixed_test(label, Direct(ColumnRle::<u8>::new()), workload, latencies),
+            TestType::NestedBTree => mixed_test(label, Direct(NestedBTree::<u8>::new()), workload, latencies),
+            TestType::ChunkedGrid => mixed_test(label, Direct(ChunkedGrid::<u8>::new()), workload, latencies),
+            TestType::Octree => mixed_test(label, Direct(Octree::<u8>::new()), workload, latencies),
+            TestType::FlatBtreeXYZ => mixed_test(label, Direct(BTreeMap::<(u16, u16, u8), u8>::new()), workload, latencies),
+            TestType::FlatBtreeZXY => mixed_test(label, Direct(BTreeMap::<(u8, u16, u16), u8>::new()), workload, latencies),
+            TestType::GenericMap => {
+                let map: GenericMap<Coord, u8> = GenericMap::with_capacity((self.iterations + 1) as usize);
+                mixed_test(label, Direct(map), workload, latencies)
+            }
+            TestType::SlotMap => {
+                let map: SlottedMap<Coord, u8> = SlottedMap::with_capacity(self.iterations as usize);
+                mixed_test(label, Direct(map), workload, latencies)
+            }
+            TestType::RegionStore => {
+                if Path::new(REGION_DIR).exists() {
+                    fs::remove_dir_all(REGION_DIR).unwrap();
+                }
+                let map: RegionStore<u8> = RegionStore::open(REGION_DIR, REGION_BUDGET).unwrap();
+                mixed_test(label, map, workload, latencies)
+            }
+        }
+    }
+    // one row per op across the runs, ops are prefixed with the access pattern
+    fn rows(&self, runs: &[TestRun], compressed: bool, bytes: Option<u64>, heap: (u64, u64), latencies: &[Vec<u64>]) -> Vec<BenchResult> {
+        let mut ops: Vec<(&str, Vec<Duration>)> = Vec::new();
+        for run in runs {
+            let mut timings = run.ops.clone();
+            if compressed {
+                timings.push(("compress", run.compress));
+                timings.push(("decompress", run.decompress));
+            }
+            timings.push(("total", run.elapsed));
+            for (op, duration) in timings {
+                match ops.iter_mut().find(|(name, _)| *name == op) {
+                    Some((_, samples)) => samples.push(duration),
+                    None => ops.push((op, vec![duration])),
+                }
+            }
         }
+        let name = |op: &str| format!("{} {}", self.pattern.key(), op);
+        let timings = ops.iter()
+            .map(|(op, samples)| BenchResult::new(self.test_type.key(), &name(op), self.iterations as u64, samples, bytes));
+        // one row per op kind of the mixed workload, every single op is a sample
+        let kinds = mixed::KINDS.iter().zip(latencies)
+            .filter(|(_, sorted)| !sorted.is_empty())
+            .map(|(kind, sorted)| {
+                let op = name(&format!("mixed {}", kind));
+                BenchResult::from_sorted(self.test_type.key(), &op, self.iterations as u64, sorted, bytes)
+            });
+        timings.chain(kinds)
+            .map(|row| BenchResult { peak_heap: Some(heap.0), retained_heap: Some(heap.1), ..row })
+            .collect()
     }
 }
 fn main() {
-    let mut tests: Vec<Test> = Vec::new();
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => options,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::usage());
+            std::process::exit(2);
+        }
+    };
+    // read before anything runs so a bad path doesn't cost a whole benchmark
+    let baseline = match options.compare.as_deref().map(report::read).transpose() {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
     let mut results: Vec<String> = Vec::new();
-    //tests.push(Test::new(5, 2_000_000, 64000, true, TestType::SlotMap));
-    tests.push(Test::new(5, 2_000_000, 64000, true, TestType::FlatBtreeXYZ));
-    tests.push(Test::new(5, 2_000_000, 64000, true, TestType::FlatBtreeZXY));
-    tests.push(Test::new(5, 2_000_000, 64000, true, TestType::DeeplyNestedBTree));
-    tests.push(Test::new(5, 2_000_000, 64000, true, TestType::NestedBTree));
+    if options.extras {
+        nested_btree_random_test(options.iterations, options.seed);
+        journal_test(options.width, options.iterations, options.seed);
+        results.extend(codec_test(options.width, options.iterations));
+        results.extend(layered_test(options.width, options.iterations, options.seed));
+        results.extend(observed_test(options.width, options.iterations, options.seed));
+        results.extend(snapshot_test(options.width, options.iterations, options.seed));
+        results.extend(parallel_test(options.width, options.iterations, options.seed));
+    }
+    let tests: Vec<Test> = options.backends.iter()
+        .map(|&backend| Test::new(&options, backend))
+        .collect();
+    let mut rows: Vec<BenchResult> = Vec::new();
     for test in tests {
         let mut min: Duration = Duration::MAX;
         let mut max: Duration = Duration::ZERO;
         let mut total = Duration::ZERO;
+        let mut compress_total = Duration::ZERO;
+        let mut decompress_total = Duration::ZERO;
+        let mut raw_size = 0;
+        let mut runs: Vec<TestRun> = Vec::new();
         for _i in 0..test.num_tests {
-            let mut val = Duration::MAX;
-            match test.test_type {
-                TestType::DeeplyNestedBTree => {
-                    val = deeply_nested_btree_test(test.width, test.iterations, test.serialize);
-                }
-                TestType::NestedBTree => {
-                    val = nested_btree_test(test.width, test.iterations, test.serialize);
-                }
-                TestType::FlatBtreeXYZ => {
-                    val = btree_test(test.width, test.iterations, false, test.serialize);
-                }
-                TestType::FlatBtreeZXY => {
-                    val = btree_test(test.width, test.iterations, true, test.serialize);
-                }
-                TestType::GenericMap => {
-                    val = generic_map_test(test.width, test.iterations, test.serialize);
-                }
-                TestType::SlotMap => {
-                    val = slot_map_test(test.width, test.iterations, test.serialize);
-                }
-            };
+            let run = test.run();
+            compress_total += run.compress;
+            decompress_total += run.decompress;
+            raw_size = run.raw_size;
+            let val = run.elapsed;
             if val < min {
                 min = val;
             }
@@ -556,56 +869,90 @@ fn main() {
                 max = val;
             }
             total += val;
+            runs.push(run);
         }
         let avg = total / test.num_tests;
-        let mut name = "";
-        match test.test_type {
-            TestType::DeeplyNestedBTree => {
-                name = "Results for deeply nested btree";
-            }
-            TestType::NestedBTree => {
-                name = "Results for nested btree";
-            }
-            TestType::FlatBtreeXYZ => {
-                name = "Results for flat b-tree as XYZ";
-            }
-            TestType::FlatBtreeZXY => {
-                name = "Results for flat b-tree as ZXY";
-            }
-            TestType::GenericMap => {
-                name = "Results for generic_map";
-            }
-            TestType::SlotMap => {
-                name = "Results for slot map";
-            }
-        };
         results.push(format!(
-            "{}: # of tests: {}, iterations = {}, width = {}, serialize={}",
-            name, test.num_tests,test.iterations, test.width, test.serialize
+            "{}: # of tests: {}, iterations = {}, width = {}, serialize={}, pattern = {}, seed = {}",
+            test.test_type.name(), test.num_tests,test.iterations, test.width, test.serialize, test.pattern.key(), test.seed
         ));
+        let mut bytes = None;
         if test.serialize {
-            let mib: f64 = (File::metadata(&File::open("test.dat").unwrap())
-                .unwrap()
-                .len()) as f64
-                / (1024.0 * 1024.0);
+            let size = take_saved_size();
+            bytes = Some(size);
+            let mib: f64 = size as f64 / (1024.0 * 1024.0);
             results.push(format!(
                 "minimum time = {:?}, maximum time = {:?}, mean time = {:?}, file size = {:.2} MiB",
                 min, max, avg, mib
             ));
-            fs::remove_file("test.dat").unwrap();
+            if test.compression != Compression::None && raw_size > 0 {
+                results.push(format!(
+                    "compression = {}, uncompressed size = {:.2} MiB, ratio = {:.2}, mean compress time = {:?}, mean decompress time = {:?}",
+                    test.compression.name(), raw_size as f64 / (1024.0 * 1024.0), raw_size as f64 / size as f64,
+                    compress_total / test.num_tests, decompress_total / test.num_tests
+                ));
+            }
         } else {
+            // the region store still pages chunks out to disk
+            if Path::new(REGION_DIR).exists() {
+                fs::remove_dir_all(REGION_DIR).unwrap();
+            }
             results.push(format!(
                 "minimum time = {:?}, maximum time = {:?}, mean time = {:?}",
                 min, max, avg
             ));
         }
+        // runs are seeded the same, so the last one stands for all of them apart from the peak
+        let peak_heap = runs.iter().map(|r| r.peak_heap).max().unwrap_or(0);
+        let last = runs.last().unwrap();
+        results.push(format!(
+            "heap peak = {:.2} MiB, retained heap = {:.2} MiB, memory_usage estimate = {:.2} MiB",
+            peak_heap as f64 / (1024.0 * 1024.0), last.retained_heap as f64 / (1024.0 * 1024.0), last.estimated_heap as f64 / (1024.0 * 1024.0)
+        ));
+        let heap = (peak_heap as u64, last.retained_heap as u64);
+        // every run's latencies pooled by kind
+        let latencies: Vec<Vec<u64>> = (0..mixed::KINDS.len())
+            .map(|kind| {
+                let mut sorted: Vec<u64> = runs.iter().flat_map(|r| r.latencies[kind].iter().map(|&ns| ns as u64)).collect();
+                sorted.sort_unstable();
+                sorted
+            })
+            .collect();
+        if let Some(mix) = test.mix {
+            results.push(format!("mixed workload: {} ops per run after the prefill, mix = {}", test.iterations, mix.key()));
+            for (kind, sorted) in mixed::KINDS.iter().zip(&latencies) {
+                results.extend(mixed::summary(kind, sorted));
+            }
+        }
+        rows.extend(test.rows(&runs, test.compression != Compression::None && raw_size > 0, bytes, heap, &latencies));
     }
     println!();
     for r in results {
         println!("{r}");
     }
+    if let Err(e) = report_results(&options, baseline.as_deref(), &rows) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+}
 
-    println!("\nPress any key to continue...");
-    let mut stdin = io::stdin();
-    let _ = stdin.read(&mut [0u8]).unwrap();
+// results file and baseline comparison, exits with 1 when something regressed
+fn report_results(options: &cli::Options, baseline: Option<&[BenchResult]>, rows: &[BenchResult]) -> Result<(), String> {
+    if let Some(path) = &options.output {
+        let format = options.format.unwrap_or_else(|| Format::from_path(path));
+        report::write(path, format, rows)?;
+        println!("\nresults written to {} as {}", path.display(), format.key());
+    }
+    if let (Some(path), Some(baseline)) = (&options.compare, baseline) {
+        let (lines, regressions) = report::compare(baseline, rows, options.threshold);
+        println!("\nCompared to {}, threshold = {}%", path.display(), options.threshold);
+        for line in lines {
+            println!("{line}");
+        }
+        println!("{} regressions", regressions);
+        if regressions > 0 {
+            std::process::exit(1);
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,163 @@
+use memmap2::Mmap;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+use crate::codec::{Archive, ArchivedChunk, Codec, LeValue};
+use crate::nestedbtree;
+use crate::savefile::{self, Header, SaveError, TAG_CHUNK, TAG_END};
+use crate::voxelstore::Coord;
+
+// tag, cx, cy, tile count, payload length
+const RECORD_LEN: usize = 13;
+
+fn truncated() -> SaveError {
+    SaveError::Corrupt("unexpected end of file".to_string())
+}
+
+#[inline(always)]
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+// read only view over a save written with the archive codec. opening only walks the record
+// headers to find where each chunk's payload sits, get answers straight from the mapped bytes
+pub struct MapView<T> {
+    map: Mmap,
+    header: Header,
+    // chunk -> start of its record (the tag) and its payload range
+    chunks: BTreeMap<(u16, u16), (usize, usize, usize)>,
+    tiles: u64,
+    value: PhantomData<T>,
+}
+
+impl<T: LeValue> MapView<T> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MapView<T>, SaveError> {
+        let file = File::open(path)?;
+        // the view never writes, but the file must not be truncated or rewritten while it's open
+        let map = unsafe { Mmap::map(&file)? };
+        let mut reader = &map[..];
+        let header = savefile::read_header(&mut reader)?;
+        if header.codec != Archive::ID {
+            return Err(SaveError::WrongCodec { expected: Archive::ID, found: header.codec });
+        }
+        let mut at = map.len() - reader.len();
+        let mut chunks = BTreeMap::new();
+        let mut tiles: u64 = 0;
+        loop {
+            match *map.get(at).ok_or_else(truncated)? {
+                TAG_CHUNK => {
+                    if at + RECORD_LEN > map.len() {
+                        return Err(truncated());
+                    }
+                    let chunk = (
+                        u16::from_le_bytes([map[at + 1], map[at + 2]]),
+                        u16::from_le_bytes([map[at + 3], map[at + 4]]),
+                    );
+                    // the tiles' z is only checked by verify, reading it here would touch every page
+                    savefile::check_chunk(chunk, [], header.dims)?;
+                    let count = u32_at(&map, at + 5) as usize;
+                    let len = u32_at(&map, at + 9) as usize;
+                    let start = at + RECORD_LEN;
+                    if start + len + 4 > map.len() {
+                        return Err(truncated());
+                    }
+                    if len != count * (2 + T::SIZE) {
+                        return Err(SaveError::Corrupt(format!("chunk {:?} payload doesn't fit {} tiles", chunk, count)));
+                    }
+                    chunks.insert(chunk, (at, start, start + len));
+                    tiles += count as u64;
+                    at = start + len + 4;
+                }
+                TAG_END => {
+                    if at + 17 > map.len() {
+                        return Err(truncated());
+                    }
+                    let end = &map[at + 1..at + 13];
+                    if crc32fast::hash(end) != u32_at(&map, at + 13) {
+                        return Err(SaveError::Corrupt("bad end record".to_string()));
+                    }
+                    let expected_chunks = u32_at(end, 0) as usize;
+                    let expected_tiles = u64::from_le_bytes(end[4..12].try_into().unwrap());
+                    if expected_chunks != chunks.len() || expected_tiles != tiles {
+                        return Err(SaveError::Corrupt("chunk or tile count mismatch".to_string()));
+                    }
+                    break;
+                }
+                other => return Err(SaveError::Corrupt(format!("unknown record tag {}", other))),
+            }
+        }
+        Ok(Self { map, header, chunks, tiles, value: PhantomData })
+    }
+    // open skips the chunk checksums and tile heights so it doesn't have to touch every page,
+    // this checks them all
+    pub fn verify(&self) -> Result<(), SaveError> {
+        for (chunk, &(at, _, end)) in &self.chunks {
+            if crc32fast::hash(&self.map[at + 1..end]) != u32_at(&self.map, end) {
+                return Err(SaveError::Checksum { chunk: *chunk });
+            }
+            let tiles = self.chunk(chunk.0, chunk.1).unwrap();
+            savefile::check_chunk(*chunk, tiles.iter().map(|t| t.1), self.header.dims)?;
+        }
+        Ok(())
+    }
+    pub fn header(&self) -> Header {
+        self.header
+    }
+    pub fn len(&self) -> usize {
+        self.tiles as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tiles == 0
+    }
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+    pub fn chunk(&self, cx: u16, cy: u16) -> Option<ArchivedChunk<'_, T>> {
+        let &(_, start, end) = self.chunks.get(&(cx, cy))?;
+        // the length was checked against the tile count in open
+        ArchivedChunk::new(&self.map[start..end]).ok()
+    }
+    pub fn get(&self, x: u16, y: u16, z: u8) -> Option<T> {
+        let (chunk, column) = nestedbtree::split(x, y);
+        self.chunk(chunk.0, chunk.1)?.get(column, z)
+    }
+    // chunk by chunk, (column, z) order inside a chunk
+    pub fn iter(&self) -> impl Iterator<Item = (Coord, T)> + '_ {
+        self.chunks.keys().flat_map(move |&(cx, cy)| {
+            self.chunk(cx, cy).unwrap().iter().map(move |(column, z, value)| {
+                let (x, y) = nestedbtree::join((cx, cy), column);
+                ((x, y, z), value)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn open_one(name: &str, dims: (u32, u32, u32), chunk: (u16, u16), z: u8) -> Result<MapView<u8>, SaveError> {
+        let path = std::env::temp_dir().join(format!("btree_test_{}_{}.dat", name, std::process::id()));
+        let mut raw = Vec::new();
+        savefile::write_header(&mut raw, 3, Archive::ID, dims).unwrap();
+        savefile::write_chunk_with::<Archive, u8, _>(&mut raw, chunk, &[(0, z, &1)]).unwrap();
+        savefile::write_end(&mut raw, 1, 1).unwrap();
+        fs::write(&path, &raw).unwrap();
+        let view = MapView::open(&path);
+        fs::remove_file(&path).unwrap();
+        view
+    }
+
+    #[test]
+    fn chunks_outside_the_map_are_rejected() {
+        assert!(matches!(open_one("view_far", (32, 32, 256), (2, 0), 0), Err(SaveError::Corrupt(_))));
+        assert!(matches!(open_one("view_huge", (u32::MAX, u32::MAX, 256), (4096, 0), 0), Err(SaveError::Corrupt(_))));
+        let view = open_one("view_high", (32, 32, 100), (1, 1), 100).unwrap();
+        assert!(matches!(view.verify(), Err(SaveError::Corrupt(_))));
+        let view = open_one("view_fits", (32, 32, 100), (1, 1), 99).unwrap();
+        assert!(view.verify().is_ok());
+        assert_eq!(view.get(16, 16, 99), Some(1));
+    }
+}
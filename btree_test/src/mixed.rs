@@ -0,0 +1,264 @@
+use btree_test::region::RegionStore;
+use btree_test::voxelstore::{Coord, VoxelStore};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+pub const KINDS: [&str; 4] = ["get", "insert", "update", "remove"];
+
+// histogram buckets double from 32ns, the first takes everything faster and the last everything slower
+const BUCKETS: usize = 16;
+const FIRST_BUCKET_BITS: u32 = 5;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Get(Coord),
+    Insert(Coord, u8),
+    // read-modify-write of a tile that's there, bumps its value by one
+    Update(Coord),
+    Remove(Coord),
+}
+impl Op {
+    fn kind(self) -> usize {
+        match self {
+            Op::Get(_) => 0,
+            Op::Insert(..) => 1,
+            Op::Update(_) => 2,
+            Op::Remove(_) => 3,
+        }
+    }
+}
+
+// share of each op in KINDS order, as weights
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mix(pub [u32; 4]);
+impl Default for Mix {
+    // mostly lookups with a steady trickle of tile changes, item moves and digging
+    fn default() -> Self {
+        Mix([70, 10, 15, 5])
+    }
+}
+impl Mix {
+    // get=70,insert=10,update=15,remove=5, kinds left out get 0
+    pub fn parse(value: &str) -> Result<Mix, String> {
+        let mut weights = [0; 4];
+        for part in value.split(',') {
+            let (name, weight) = part.split_once('=')
+                .ok_or_else(|| format!("--mix expects kind=weight pairs, got '{}'", part))?;
+            let kind = KINDS.iter().position(|k| *k == name.trim())
+                .ok_or_else(|| format!("unknown op '{}', expected one of: {}", name, KINDS.join(", ")))?;
+            weights[kind] = weight.trim().parse()
+                .map_err(|_| format!("--mix weight for {} expects a number, got '{}'", name, weight))?;
+        }
+        if weights.iter().sum::<u32>() == 0 {
+            return Err("--mix needs at least one weight above 0".to_string());
+        }
+        Ok(Mix(weights))
+    }
+    pub fn key(self) -> String {
+        KINDS.iter().zip(self.0).map(|(k, w)| format!("{}={}", k, w)).collect::<Vec<_>>().join(",")
+    }
+}
+
+// tiles the map starts with and the ops run against it afterwards, all worked out before anything
+// is timed so every backend sees exactly the same stream
+pub struct Workload {
+    pub prefill: Vec<Coord>,
+    pub ops: Vec<Op>,
+    // what every tile should hold once the ops are done, and tiles that must be gone
+    expected: HashMap<Coord, u8>,
+    removed: Vec<Coord>,
+}
+
+impl Workload {
+    pub fn new(prefill: &[Coord], ops: usize, mix: Mix, width: u16, seed: u64) -> Workload {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut expected: HashMap<Coord, u8> = HashMap::with_capacity(prefill.len());
+        // live tiles as a vector too so one can be picked at random, index finds its slot
+        let mut live: Vec<Coord> = Vec::with_capacity(prefill.len());
+        let mut index: HashMap<Coord, usize> = HashMap::with_capacity(prefill.len());
+        for &c in prefill {
+            if expected.insert(c, c.2).is_none() {
+                index.insert(c, live.len());
+                live.push(c);
+            }
+        }
+        let total: u32 = mix.0.iter().sum();
+        let mut stream = Vec::with_capacity(ops);
+        for _ in 0..ops {
+            let mut roll = rng.gen_range(0..total);
+            let mut kind = 0;
+            while roll >= mix.0[kind] {
+                roll -= mix.0[kind];
+                kind += 1;
+            }
+            // nothing left to read or change, put something back instead
+            if live.is_empty() {
+                kind = 1;
+            }
+            let op = match kind {
+                0 => Op::Get(live[rng.gen_range(0..live.len())]),
+                1 => {
+                    let c = (rng.gen_range(0..width), rng.gen_range(0..width), rng.gen_range(0..255));
+                    let value = rng.gen();
+                    if expected.insert(c, value).is_none() {
+                        index.insert(c, live.len());
+                        live.push(c);
+                    }
+                    Op::Insert(c, value)
+                }
+                2 => {
+                    let c = live[rng.gen_range(0..live.len())];
+                    let value = expected.get_mut(&c).unwrap();
+                    *value = value.wrapping_add(1);
+                    Op::Update(c)
+                }
+                _ => {
+                    let c = live.swap_remove(rng.gen_range(0..live.len()));
+                    let at = index.remove(&c).unwrap();
+                    if let Some(moved) = live.get(at) {
+                        index.insert(*moved, at);
+                    }
+                    expected.remove(&c);
+                    Op::Remove(c)
+                }
+            };
+            stream.push(op);
+        }
+        let removed = stream.iter()
+            .filter_map(|op| match op {
+                Op::Remove(c) if !expected.contains_key(c) => Some(*c),
+                _ => None,
+            })
+            .collect();
+        Self { prefill: prefill.to_vec(), ops: stream, expected, removed }
+    }
+    // one latency buffer per kind, sized up front so filling them doesn't show up in the timings or the heap
+    pub fn buffers(&self) -> [Vec<u32>; 4] {
+        let mut counts = [0; 4];
+        for op in &self.ops {
+            counts[op.kind()] += 1;
+        }
+        counts.map(Vec::with_capacity)
+    }
+}
+
+// what the op stream needs from a map, the region store's calls can fail so it's wrapped here
+pub trait Target {
+    fn get(&mut self, c: Coord) -> Option<u8>;
+    fn insert(&mut self, c: Coord, value: u8);
+    fn update(&mut self, c: Coord);
+    fn remove(&mut self, c: Coord);
+    fn memory_usage(&self) -> usize;
+}
+
+pub struct Direct<M>(pub M);
+
+impl<M: VoxelStore<u8>> Target for Direct<M> {
+    #[inline(always)]
+    fn get(&mut self, c: Coord) -> Option<u8> {
+        self.0.get(c.0, c.1, c.2).copied()
+    }
+    #[inline(always)]
+    fn insert(&mut self, c: Coord, value: u8) {
+        self.0.insert(c.0, c.1, c.2, value);
+    }
+    #[inline(always)]
+    fn update(&mut self, c: Coord) {
+        if let Some(value) = self.0.get_mut(c.0, c.1, c.2) {
+            *value = value.wrapping_add(1);
+        }
+    }
+    #[inline(always)]
+    fn remove(&mut self, c: Coord) {
+        self.0.remove(c.0, c.1, c.2);
+    }
+    fn memory_usage(&self) -> usize {
+        self.0.memory_usage()
+    }
+}
+
+impl Target for RegionStore<u8> {
+    fn get(&mut self, c: Coord) -> Option<u8> {
+        RegionStore::get(self, c.0, c.1, c.2).unwrap().copied()
+    }
+    fn insert(&mut self, c: Coord, value: u8) {
+        RegionStore::insert(self, c.0, c.1, c.2, value).unwrap();
+    }
+    fn update(&mut self, c: Coord) {
+        if let Some(value) = RegionStore::get_mut(self, c.0, c.1, c.2).unwrap() {
+            *value = value.wrapping_add(1);
+        }
+    }
+    fn remove(&mut self, c: Coord) {
+        RegionStore::remove(self, c.0, c.1, c.2).unwrap();
+    }
+    fn memory_usage(&self) -> usize {
+        self.memory_used()
+    }
+}
+
+// runs the stream and records every op's latency in ns by kind, returns the wall time of the lot
+pub fn run<T: Target>(map: &mut T, workload: &Workload, latencies: &mut [Vec<u32>; 4]) -> Duration {
+    let start = Instant::now();
+    for &op in &workload.ops {
+        let t = Instant::now();
+        match op {
+            Op::Get(c) => {
+                black_box(map.get(c));
+            }
+            Op::Insert(c, value) => map.insert(c, value),
+            Op::Update(c) => map.update(c),
+            Op::Remove(c) => map.remove(c),
+        }
+        latencies[op.kind()].push(t.elapsed().as_nanos().min(u32::MAX as u128) as u32);
+    }
+    start.elapsed()
+}
+
+// every tile the stream should have left holds what it should, and everything it removed is gone
+pub fn check<T: Target>(map: &mut T, workload: &Workload) -> bool {
+    workload.expected.iter().all(|(c, v)| map.get(*c) == Some(*v))
+        && workload.removed.iter().all(|c| map.get(*c).is_none())
+}
+
+#[inline(always)]
+fn bucket(ns: u32) -> usize {
+    ((32 - ns.leading_zeros()).saturating_sub(FIRST_BUCKET_BITS) as usize).min(BUCKETS - 1)
+}
+
+fn bucket_label(i: usize) -> String {
+    let ns = |bits: u32| Duration::from_nanos(1 << bits);
+    match i {
+        0 => format!("<{:?}", ns(FIRST_BUCKET_BITS)),
+        i if i == BUCKETS - 1 => format!(">={:?}", ns(FIRST_BUCKET_BITS + i as u32 - 1)),
+        i => format!("<{:?}", ns(FIRST_BUCKET_BITS + i as u32)),
+    }
+}
+
+// throughput, percentiles and a latency histogram for every kind that ran, sorted is in ns
+pub fn summary(kind: &str, sorted: &[u64]) -> Vec<String> {
+    if sorted.is_empty() {
+        return vec![];
+    }
+    let n = sorted.len();
+    let total: u64 = sorted.iter().sum();
+    let at = |p: usize| Duration::from_nanos(sorted[(n * p).div_ceil(100).max(1) - 1]);
+    let mut counts = [0usize; BUCKETS];
+    for &ns in sorted {
+        counts[bucket(ns.min(u32::MAX as u64) as u32)] += 1;
+    }
+    let histogram: Vec<String> = counts.iter().enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(i, count)| format!("{} {:.1}%", bucket_label(i), *count as f64 * 100.0 / n as f64))
+        .collect();
+    vec![
+        format!(
+            "  {}: {} ops, {:.2} Mops/s, p50 = {:?}, p95 = {:?}, p99 = {:?}, max = {:?}",
+            kind, n, n as f64 * 1000.0 / total.max(1) as f64, at(50), at(95), at(99), at(100)
+        ),
+        format!("    {}", histogram.join(" | ")),
+    ]
+}
@@ -1,100 +1,307 @@
+use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
-use std::collections::{BTreeMap};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use crate::journal::DirtyChunks;
+use crate::savefile::{ChunkTiles, SaveChunks, SaveError};
+use crate::voxelstore::{btree_memory, Coord, VoxelStore};
+
+pub const CHUNK_SIZE: u16 = 16;
+
+// x = cx * 16 + lx, y = cy * 16 + ly, tiles in a chunk are keyed by (lx * 16 + ly, z) so every
+// z column is contiguous and a chunk iterates in x,y,z order
+#[inline(always)]
+pub fn split(x: u16, y: u16) -> ((u16, u16), u8) {
+    ((x / CHUNK_SIZE, y / CHUNK_SIZE), ((x % CHUNK_SIZE) * CHUNK_SIZE + y % CHUNK_SIZE) as u8)
+}
+
+#[inline(always)]
+pub fn join(chunk: (u16, u16), column: u8) -> (u16, u16) {
+    let column = column as u16;
+    (chunk.0 * CHUNK_SIZE + column / CHUNK_SIZE, chunk.1 * CHUNK_SIZE + column % CHUNK_SIZE)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chunk<T> {
+    tiles: BTreeMap<(u8, u8), T>,
+}
+
+impl<T> Chunk<T> {
+    pub fn new() -> Chunk<T> {
+        Self {
+            tiles: BTreeMap::new(),
+        }
+    }
+    #[inline(always)]
+    pub fn get(&self, column: u8, z: u8) -> Option<&T> {
+        self.tiles.get(&(column, z))
+    }
+    #[inline(always)]
+    pub fn get_mut(&mut self, column: u8, z: u8) -> Option<&mut T> {
+        self.tiles.get_mut(&(column, z))
+    }
+    #[inline(always)]
+    pub fn insert(&mut self, column: u8, z: u8, value: T) -> Option<T> {
+        self.tiles.insert((column, z), value)
+    }
+    pub fn remove(&mut self, column: u8, z: u8) -> Option<T> {
+        self.tiles.remove(&(column, z))
+    }
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+    // (column, z) order
+    pub fn iter(&self) -> impl Iterator<Item = ((u8, u8), &T)> + '_ {
+        self.tiles.iter().map(|(k, v)| (*k, v))
+    }
+    // rough heap estimate, see btree_memory
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() + btree_memory::<(u8, u8), T>(self.tiles.len())
+    }
+}
+
+impl<T> Default for Chunk<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// one cx worth of the chunks range_box visits, with their cy
+type Band<'a, T> = (u16, Vec<(u16, &'a Chunk<T>)>);
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NestedBTree<T> {
-    x65535y65535: BTreeMap<(u8, u8), BTreeMap<(u8, u8, u8), T>>,
-    x65535y255: BTreeMap<(u8, u8), BTreeMap<(u8, u8, u8), T>>,
-    x255y65535: BTreeMap<(u8, u8), BTreeMap<(u8, u8, u8), T>>,
-    x255y255: BTreeMap<(u8, u8), BTreeMap<(u8, u8, u8), T>>,
+    // chunks can be shared with snapshots, a write to a shared chunk copies it first so the
+    // snapshot keeps the tiles it was taken with
+    chunks: BTreeMap<(u16, u16), Arc<Chunk<T>>>,
+    len: usize,
+    #[serde(skip)]
+    dirty: BTreeSet<(u16, u16)>,
 }
 
 impl<T> NestedBTree<T> {
     pub fn new() -> NestedBTree<T> {
         Self {
-            x255y255: BTreeMap::new(),
-            x255y65535: BTreeMap::new(),
-            x65535y255: BTreeMap::new(),
-            x65535y65535: BTreeMap::new(),
+            chunks: BTreeMap::new(),
+            len: 0,
+            dirty: BTreeSet::new(),
         }
     }
-    #[inline(always)]
-    pub fn get (&self, x:u16, y:u16, z: u8) -> Result<&T, &'static str> {
-        if x > 255 && y > 255 {
-            let child = match self.x65535y65535.get(&((x/256) as u8, (y/256) as u8)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            let child = match child.get(&((x%256) as u8, (y%256) as u8, z)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            return Ok(&child);
-        }
-        else if x > 255 {
-            let child = match self.x65535y255.get(&((x/256) as u8, y as u8)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            let child = match child.get(&((x%256) as u8, y as u8, z)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            return Ok(&child);
-        }
-        else if y > 255 {
-            let child = match self.x255y65535.get(&(x as u8, (y/256) as u8)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            let child = match child.get(&(x as u8, (y%256) as u8, z)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            return Ok(&child);
-        }
-        else {
-            let child = match self.x255y255.get(&(x as u8, y as u8)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            let child = match child.get(&(x as u8, y as u8, z)) {
-                None => return Err("Not found"),
-                Some(s) => s,
-            };
-            return Ok(&child);
+    pub fn chunk(&self, cx: u16, cy: u16) -> Option<&Chunk<T>> {
+        self.chunks.get(&(cx, cy)).map(Arc::as_ref)
+    }
+    pub fn chunks(&self) -> impl Iterator<Item = ((u16, u16), &Chunk<T>)> + '_ {
+        self.chunks.iter().map(|(k, v)| (*k, v.as_ref()))
+    }
+    // frozen copy of the map as it is now, sharing every chunk instead of copying tiles, so it
+    // costs one pointer per chunk. the live map copies a chunk the first time it writes to it
+    // afterwards, so a background thread can save the snapshot while the game keeps going. the
+    // snapshot starts with the same dirty chunks, the live map keeps its own
+    pub fn snapshot(&self) -> NestedBTree<T> {
+        Self {
+            chunks: self.chunks.clone(),
+            len: self.len,
+            dirty: self.dirty.clone(),
         }
     }
-    #[inline(always)]
-    pub fn insert(&mut self, x:u16, y:u16, z: u8, value: T) {
-        if x > 255 && y > 255 {
-            if !self.x65535y65535.contains_key(&((x/256) as u8, (y/256) as u8)){
-                self.x65535y65535.insert(((x/256) as u8, (y/256) as u8), BTreeMap::new());
+    // chunks this map and other hold the same copy of
+    pub fn shared_chunks(&self, other: &NestedBTree<T>) -> usize {
+        self.chunks.iter()
+            .filter(|(k, c)| other.chunks.get(k).is_some_and(|o| Arc::ptr_eq(c, o)))
+            .count()
+    }
+    // everything in x,y,z order
+    pub fn iter(&self) -> impl Iterator<Item = (Coord, &T)> + '_ {
+        self.range_box((0, 0, 0), (u16::MAX, u16::MAX, u8::MAX))
+    }
+    pub fn iter_z_level(&self, z: u8) -> impl Iterator<Item = (Coord, &T)> + '_ {
+        self.range_box((0, 0, z), (u16::MAX, u16::MAX, z))
+    }
+    // every tile inside the inclusive box min..=max in x,y,z order, only chunks that
+    // intersect the box are visited
+    pub fn range_box(&self, min: Coord, max: Coord) -> impl Iterator<Item = (Coord, &T)> + '_ {
+        // BTreeMap::range panics on an inverted range
+        let empty = min.0 > max.0 || min.1 > max.1 || min.2 > max.2;
+        let (z0, z1) = (min.2, max.2);
+        let (cy0, cy1) = (min.1 / CHUNK_SIZE, max.1 / CHUNK_SIZE);
+        // one walk over the chunk keys, grouped by cx since every x in a cx comes before the next
+        let mut bands: Vec<Band<T>> = Vec::new();
+        if !empty {
+            for (&(cx, cy), chunk) in self.chunks.range((min.0 / CHUNK_SIZE, cy0)..=(max.0 / CHUNK_SIZE, cy1)) {
+                if cy < cy0 || cy > cy1 {
+                    continue;
+                }
+                match bands.last_mut() {
+                    Some((last, chunks)) if *last == cx => chunks.push((cy, chunk)),
+                    _ => bands.push((cx, vec![(cy, chunk.as_ref())])),
+                }
             }
-            let child = self.x65535y65535.get_mut(&((x/256) as u8, (y/256) as u8)).unwrap();
-            child.insert(((x%256) as u8, (y%256) as u8, z), value);
         }
-        else if x > 255 {
-            if !self.x65535y255.contains_key(&((x/256) as u8, y as u8)){
-                self.x65535y255.insert(((x/256) as u8, y as u8), BTreeMap::new());
-            }
-            let child = self.x65535y255.get_mut(&((x/256) as u8, y as u8)).unwrap();
-            child.insert(((x%256) as u8, y as u8, z), value);
+        bands.into_iter().flat_map(move |(cx, chunks)| {
+            let base_x = cx * CHUNK_SIZE;
+            let lx0 = min.0.max(base_x) - base_x;
+            let lx1 = max.0.min(base_x + (CHUNK_SIZE - 1)) - base_x;
+            (lx0..=lx1).flat_map(move |lx| {
+                chunks.clone().into_iter().flat_map(move |(cy, chunk)| {
+                    let base = cy * CHUNK_SIZE;
+                    let ly0 = min.1.max(base) - base;
+                    let ly1 = max.1.min(base + (CHUNK_SIZE - 1)) - base;
+                    let c0 = (lx * CHUNK_SIZE + ly0) as u8;
+                    let c1 = (lx * CHUNK_SIZE + ly1) as u8;
+                    chunk.tiles.range((c0, z0)..=(c1, z1))
+                        .filter(move |(k, _)| k.1 >= z0 && k.1 <= z1)
+                        .map(move |(k, v)| ((base_x + lx, base + k.0 as u16 % CHUNK_SIZE, k.1), v))
+                })
+            })
+        })
+    }
+}
+
+impl<T: Clone + Send + Sync> NestedBTree<T> {
+    // groups the tiles by chunk and fills the chunks on the rayon pool, a tile given twice keeps
+    // the later value. returns how many tiles are new
+    pub fn par_insert_batch(&mut self, tiles: Vec<(Coord, T)>) -> usize {
+        let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for ((x, y, z), value) in tiles {
+            let (chunk, column) = split(x, y);
+            groups.entry(chunk).or_default().push((column, z, value));
         }
-        else if y > 255 {
-            if !self.x255y65535.contains_key(&(x as u8, (y/256) as u8)){
-                self.x255y65535.insert((x as u8, (y/256) as u8), BTreeMap::new());
-            }
-            let child = self.x255y65535.get_mut(&(x as u8, (y/256) as u8)).unwrap();
-            child.insert((x as u8, (y%256) as u8, z), value);
+        // chunks are taken out while they're filled so every worker owns the one it's on
+        let jobs: Vec<_> = groups.into_iter()
+            .map(|(chunk, tiles)| (chunk, self.chunks.remove(&chunk).unwrap_or_default(), tiles))
+            .collect();
+        let filled: Vec<_> = jobs.into_par_iter()
+            .map(|(key, mut chunk, tiles)| {
+                let mut added = 0;
+                let chunk_mut = Arc::make_mut(&mut chunk);
+                for (column, z, value) in tiles {
+                    if chunk_mut.insert(column, z, value).is_none() {
+                        added += 1;
+                    }
+                }
+                (key, chunk, added)
+            })
+            .collect();
+        let mut total = 0;
+        for (key, chunk, added) in filled {
+            self.chunks.insert(key, chunk);
+            self.dirty.insert(key);
+            total += added;
         }
-        else {
-            if !self.x255y255.contains_key(&(x as u8, y as u8)){
-                self.x255y255.insert((x as u8, y as u8), BTreeMap::new());
-            }
-            let child = self.x255y255.get_mut(&(x as u8, y as u8)).unwrap();
-            child.insert((x as u8, y as u8, z), value);
+        self.len += total;
+        total
+    }
+}
+
+impl<T: Send + Sync> NestedBTree<T> {
+    // every tile with the chunks split over the rayon pool, collecting keeps chunk by chunk order
+    pub fn par_iter(&self) -> impl ParallelIterator<Item = (Coord, &T)> + '_ {
+        self.chunks.par_iter().flat_map_iter(|(&key, chunk)| {
+            chunk.iter().map(move |((column, z), v)| {
+                let (x, y) = join(key, column);
+                ((x, y, z), v)
+            })
+        })
+    }
+}
+
+impl<T> Default for NestedBTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> VoxelStore<T> for NestedBTree<T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        let (chunk, column) = split(x, y);
+        self.chunks.get(&chunk)?.get(column, z)
+    }
+    #[inline(always)]
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        let (chunk, column) = split(x, y);
+        let child = self.chunks.get_mut(&chunk)?;
+        // missing tiles don't cost a copy of a shared chunk
+        child.get(column, z)?;
+        let value = Arc::make_mut(child).get_mut(column, z)?;
+        self.dirty.insert(chunk);
+        Some(value)
+    }
+    #[inline(always)]
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        let (chunk, column) = split(x, y);
+        self.dirty.insert(chunk);
+        let old = Arc::make_mut(self.chunks.entry(chunk).or_default()).insert(column, z, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        let (chunk, column) = split(x, y);
+        let child = self.chunks.get_mut(&chunk)?;
+        child.get(column, z)?;
+        let child = Arc::make_mut(child);
+        let value = child.remove(column, z)?;
+        if child.is_empty() {
+            self.chunks.remove(&chunk);
         }
+        self.dirty.insert(chunk);
+        self.len -= 1;
+        Some(value)
+    }
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T {
+        let (chunk, column) = split(x, y);
+        self.dirty.insert(chunk);
+        let len = &mut self.len;
+        Arc::make_mut(self.chunks.entry(chunk).or_default()).tiles.entry((column, z)).or_insert_with(|| {
+            *len += 1;
+            default()
+        })
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(NestedBTree::iter(self))
+    }
+    fn memory_usage(&self) -> usize {
+        // every chunk sits in its own Arc next to the two counts, chunks shared with a snapshot
+        // are counted here as well as there
+        let chunk = std::mem::size_of::<Chunk<T>>() + 2 * std::mem::size_of::<usize>();
+        let tiles: usize = self.chunks.values().map(|c| chunk + btree_memory::<(u8, u8), T>(c.len())).sum();
+        std::mem::size_of::<Self>()
+            + btree_memory::<(u16, u16), Arc<Chunk<T>>>(self.chunks.len())
+            + tiles
+            + btree_memory::<(u16, u16), ()>(self.dirty.len())
+    }
+}
+
+impl<T> DirtyChunks<T> for NestedBTree<T> {
+    fn dirty_chunks(&self) -> Vec<(u16, u16)> {
+        self.dirty.iter().copied().collect()
+    }
+    fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+    fn chunk_tiles(&self, chunk: (u16, u16)) -> ChunkTiles<'_, T> {
+        self.chunks.get(&chunk).into_iter().flat_map(|c| c.iter()).map(|((column, z), v)| (column, z, v)).collect()
+    }
+}
+
+impl<T: Clone> SaveChunks<T> for NestedBTree<T> {
+    fn for_each_chunk<'a, F>(&'a self, mut f: F) -> Result<(), SaveError>
+    where
+        T: 'a,
+        F: FnMut((u16, u16), ChunkTiles<'a, T>) -> Result<(), SaveError>,
+    {
+        for &chunk in self.chunks.keys() {
+            f(chunk, self.chunk_tiles(chunk))?;
+        }
+        Ok(())
     }
 }
 
@@ -102,66 +309,171 @@ impl<T> DeeplyNestedBTree<T> {
     pub fn new() -> DeeplyNestedBTree<T> {
         Self {
             buf: BTreeMap::new(),
+            len: 0,
+            dirty: BTreeSet::new(),
         }
     }
+    fn column(&self, x: u16, y: u16) -> Option<&Branch<T>> {
+        let child = self.buf.get(&((x / 256) as u8))?;
+        let child = child.buf.get(&((x % 256) as u8))?;
+        let child = child.buf.get(&((y / 256) as u8))?;
+        child.buf.get(&((y % 256) as u8))
+    }
+}
+
+impl<T> Default for DeeplyNestedBTree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> VoxelStore<T> for DeeplyNestedBTree<T> {
     #[inline(always)]
-    pub fn get(&self, x: u16, y:u16, z: u8) -> Option<&T> {
+    fn get(&self, x: u16, y:u16, z: u8) -> Option<&T> {
         let x2a = (x / 256) as u8;
         let x2b = (x % 256) as u8;
         let y2a = (y / 256) as u8;
         let y2b = (y % 256) as u8;
-        let child = match self.buf.get(&x2a) {
-            None => return None,
-            Some(s) => s,
-        };
-        let child = match child.buf.get(&x2b) {
-            None => return None,
-            Some(s) => s,
-        };
-        let child = match child.buf.get(&y2a) {
-            None => return None,
-            Some(s) => s,
-        };
-        let child = match child.buf.get(&y2b) {
-            None => return None,
-            Some(s) => s,
-        };
-        let child = match child.buf.get(&z) {
-            None => return None,
-            Some(s) => s,
-        };
-        Some(child)
+        let child = self.buf.get(&x2a)?;
+        let child = child.buf.get(&x2b)?;
+        let child = child.buf.get(&y2a)?;
+        let child = child.buf.get(&y2b)?;
+        child.buf.get(&z)
     }
     #[inline(always)]
-    pub fn insert(&mut self, x:u16, y:u16, z:u8, value: T) {
+    fn get_mut(&mut self, x: u16, y:u16, z: u8) -> Option<&mut T> {
         let x2a = (x / 256) as u8;
         let x2b = (x % 256) as u8;
         let y2a = (y / 256) as u8;
         let y2b = (y % 256) as u8;
-        if !self.buf.contains_key(&x2a) {
-            self.buf.insert(x2a, Branch4::new());
-        }
-        let child = self.buf.get_mut(&x2a).unwrap();
-        if !child.buf.contains_key(&x2b) {
-            child.buf.insert(x2b, Branch3::new());
+        let child = self.buf.get_mut(&x2a)?;
+        let child = child.buf.get_mut(&x2b)?;
+        let child = child.buf.get_mut(&y2a)?;
+        let child = child.buf.get_mut(&y2b)?;
+        let value = child.buf.get_mut(&z)?;
+        self.dirty.insert(split(x, y).0);
+        Some(value)
+    }
+    #[inline(always)]
+    fn insert(&mut self, x:u16, y:u16, z:u8, value: T) -> Option<T> {
+        self.dirty.insert(split(x, y).0);
+        let x2a = (x / 256) as u8;
+        let x2b = (x % 256) as u8;
+        let y2a = (y / 256) as u8;
+        let y2b = (y % 256) as u8;
+        let child = self.buf.entry(x2a).or_insert_with(Branch4::new);
+        let child = child.buf.entry(x2b).or_insert_with(Branch3::new);
+        let child = child.buf.entry(y2a).or_insert_with(Branch2::new);
+        let child = child.buf.entry(y2b).or_insert_with(Branch::new);
+        let old = child.buf.insert(z, value);
+        if old.is_none() {
+            self.len += 1;
         }
-        let child = child.buf.get_mut(&x2b).unwrap();
-        if !child.buf.contains_key(&y2a) {
-            child.buf.insert(y2a, Branch2::new());
+        old
+    }
+    fn remove(&mut self, x: u16, y:u16, z: u8) -> Option<T> {
+        let x2a = (x / 256) as u8;
+        let x2b = (x % 256) as u8;
+        let y2a = (y / 256) as u8;
+        let y2b = (y % 256) as u8;
+        // prune every branch left empty so dug out areas don't keep their maps around
+        let b4 = self.buf.get_mut(&x2a)?;
+        let b3 = b4.buf.get_mut(&x2b)?;
+        let b2 = b3.buf.get_mut(&y2a)?;
+        let b = b2.buf.get_mut(&y2b)?;
+        let value = b.buf.remove(&z)?;
+        self.dirty.insert(split(x, y).0);
+        self.len -= 1;
+        if b.buf.is_empty() {
+            b2.buf.remove(&y2b);
+            if b2.buf.is_empty() {
+                b3.buf.remove(&y2a);
+                if b3.buf.is_empty() {
+                    b4.buf.remove(&x2b);
+                    if b4.buf.is_empty() {
+                        self.buf.remove(&x2a);
+                    }
+                }
+            }
         }
-        let child = child.buf.get_mut(&y2a).unwrap();
-        if !child.buf.contains_key(&y2b) {
-            child.buf.insert(y2b, Branch::new());
+        Some(value)
+    }
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T {
+        self.dirty.insert(split(x, y).0);
+        let x2a = (x / 256) as u8;
+        let x2b = (x % 256) as u8;
+        let y2a = (y / 256) as u8;
+        let y2b = (y % 256) as u8;
+        let child = self.buf.entry(x2a).or_insert_with(Branch4::new);
+        let child = child.buf.entry(x2b).or_insert_with(Branch3::new);
+        let child = child.buf.entry(y2a).or_insert_with(Branch2::new);
+        let child = child.buf.entry(y2b).or_insert_with(Branch::new);
+        let len = &mut self.len;
+        child.buf.entry(z).or_insert_with(|| {
+            *len += 1;
+            default()
+        })
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(self.buf.iter().flat_map(|(x2a, b4)| {
+            b4.buf.iter().flat_map(move |(x2b, b3)| {
+                let x = *x2a as u16 * 256 + *x2b as u16;
+                b3.buf.iter().flat_map(move |(y2a, b2)| {
+                    b2.buf.iter().flat_map(move |(y2b, b)| {
+                        let y = *y2a as u16 * 256 + *y2b as u16;
+                        b.buf.iter().map(move |(z, v)| ((x, y, *z), v))
+                    })
+                })
+            })
+        }))
+    }
+    fn memory_usage(&self) -> usize {
+        let mut total = std::mem::size_of::<Self>()
+            + btree_memory::<u8, Branch4<T>>(self.buf.len())
+            + btree_memory::<(u16, u16), ()>(self.dirty.len());
+        for b4 in self.buf.values() {
+            total += btree_memory::<u8, Branch3<T>>(b4.buf.len());
+            for b3 in b4.buf.values() {
+                total += btree_memory::<u8, Branch2<T>>(b3.buf.len());
+                for b2 in b3.buf.values() {
+                    total += btree_memory::<u8, Branch<T>>(b2.buf.len());
+                    total += b2.buf.values().map(|b| btree_memory::<u8, T>(b.buf.len())).sum::<usize>();
+                }
+            }
         }
-        let child = child.buf.get_mut(&y2b).unwrap();
-        child.buf.insert(z, value);
+        total
+    }
+}
 
+impl<T> DirtyChunks<T> for DeeplyNestedBTree<T> {
+    fn dirty_chunks(&self) -> Vec<(u16, u16)> {
+        self.dirty.iter().copied().collect()
+    }
+    fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+    fn chunk_tiles(&self, chunk: (u16, u16)) -> ChunkTiles<'_, T> {
+        let mut tiles = Vec::new();
+        for column in 0..=u8::MAX {
+            let (x, y) = join(chunk, column);
+            if let Some(b) = self.column(x, y) {
+                tiles.extend(b.buf.iter().map(|(z, v)| (column, *z, v)));
+            }
+        }
+        tiles
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DeeplyNestedBTree<T> {
     buf: BTreeMap<u8, Branch4<T>>,
+    len: usize,
+    // 16x16 chunks, same split as NestedBTree
+    #[serde(skip)]
+    dirty: BTreeSet<(u16, u16)>,
 }
 #[derive(Serialize, Deserialize, Debug)]
 struct Branch<T> {
@@ -211,3 +523,109 @@ impl<T> Branch4<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // mostly near the ends of the axis so the first and last chunks get plenty of tiles
+    fn axis(rng: &mut StdRng) -> u16 {
+        match rng.gen_range(0..4) {
+            0 => rng.gen_range(0..40),
+            1 => rng.gen_range(u16::MAX - 40..=u16::MAX),
+            _ => rng.gen(),
+        }
+    }
+
+    // box bounds: the axis limits, something near a tile, or anywhere
+    fn bound(rng: &mut StdRng) -> u16 {
+        match rng.gen_range(0..5) {
+            0 => 0,
+            1 => u16::MAX,
+            2 => axis(rng),
+            _ => rng.gen(),
+        }
+    }
+
+    fn reference_box(reference: &BTreeMap<Coord, u32>, min: Coord, max: Coord) -> Vec<(Coord, u32)> {
+        reference.iter()
+            .filter(|(c, _)| c.0 >= min.0 && c.0 <= max.0 && c.1 >= min.1 && c.1 <= max.1 && c.2 >= min.2 && c.2 <= max.2)
+            .map(|(c, v)| (*c, *v))
+            .collect()
+    }
+
+    #[test]
+    fn matches_flat_btree() {
+        for seed in 0..8 {
+            let mut map: NestedBTree<u32> = NestedBTree::new();
+            let mut reference: BTreeMap<Coord, u32> = BTreeMap::new();
+            let mut rng = StdRng::seed_from_u64(seed);
+            for _ in 0..20_000 {
+                let (x, y, z) = (axis(&mut rng), axis(&mut rng), rng.gen());
+                if rng.gen_ratio(1, 4) {
+                    assert_eq!(map.remove(x, y, z), reference.remove(&(x, y, z)));
+                } else {
+                    let val = rng.gen();
+                    assert_eq!(map.insert(x, y, z, val), reference.insert((x, y, z), val));
+                }
+            }
+            assert_eq!(map.len(), reference.len());
+            assert!(map.iter().eq(reference.iter().map(|(c, v)| (*c, v))));
+            assert!(reference.iter().all(|(c, v)| map.get(c.0, c.1, c.2) == Some(v)));
+            for z in [0, 1, rng.gen(), u8::MAX] {
+                let level: Vec<(Coord, u32)> = map.iter_z_level(z).map(|(c, v)| (c, *v)).collect();
+                assert_eq!(level, reference_box(&reference, (0, 0, z), (u16::MAX, u16::MAX, z)));
+            }
+        }
+    }
+
+    #[test]
+    fn range_box_matches_flat_btree() {
+        let mut rng = StdRng::seed_from_u64(0xB0C5);
+        let mut map: NestedBTree<u32> = NestedBTree::new();
+        let mut reference: BTreeMap<Coord, u32> = BTreeMap::new();
+        for _ in 0..20_000 {
+            let (x, y, z, val) = (axis(&mut rng), axis(&mut rng), rng.gen(), rng.gen());
+            map.insert(x, y, z, val);
+            reference.insert((x, y, z), val);
+        }
+        // the corner tiles themselves
+        for &(x, y) in &[(0, 0), (0, u16::MAX), (u16::MAX, 0), (u16::MAX, u16::MAX)] {
+            for z in [0, u8::MAX] {
+                map.insert(x, y, z, 7);
+                reference.insert((x, y, z), 7);
+            }
+        }
+        for _ in 0..300 {
+            // inverted boxes are left in, they have to come back empty
+            let min = (bound(&mut rng), bound(&mut rng), rng.gen());
+            let max = (bound(&mut rng), bound(&mut rng), rng.gen());
+            let got: Vec<(Coord, u32)> = map.range_box(min, max).map(|(c, v)| (c, *v)).collect();
+            assert_eq!(got, reference_box(&reference, min, max), "box {:?} ..= {:?}", min, max);
+        }
+        let all = ((0, 0, 0), (u16::MAX, u16::MAX, u8::MAX));
+        assert_eq!(map.range_box(all.0, all.1).count(), reference.len());
+        let last = ((u16::MAX - 15, u16::MAX - 15, 0), (u16::MAX, u16::MAX, u8::MAX));
+        let got: Vec<(Coord, u32)> = map.range_box(last.0, last.1).map(|(c, v)| (c, *v)).collect();
+        assert_eq!(got, reference_box(&reference, last.0, last.1));
+    }
+
+    #[test]
+    fn deeply_nested_counts_its_tiles() {
+        let mut rng = StdRng::seed_from_u64(0xDEE9);
+        let mut map: DeeplyNestedBTree<u32> = DeeplyNestedBTree::new();
+        let mut reference: BTreeMap<Coord, u32> = BTreeMap::new();
+        for _ in 0..20_000 {
+            let (x, y, z, val) = (axis(&mut rng), axis(&mut rng), rng.gen(), rng.gen());
+            match rng.gen_range(0..4) {
+                0 => assert_eq!(map.remove(x, y, z), reference.remove(&(x, y, z))),
+                1 => assert_eq!(map.get_or_insert_with(x, y, z, || val), reference.entry((x, y, z)).or_insert(val)),
+                _ => assert_eq!(map.insert(x, y, z, val), reference.insert((x, y, z), val)),
+            }
+        }
+        assert_eq!(map.len(), reference.len());
+        assert!(map.iter().eq(reference.iter().map(|(c, v)| (*c, v))));
+    }
+}
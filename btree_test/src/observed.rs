@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use crate::nestedbtree::split;
+use crate::voxelstore::{btree_memory, Coord, VoxelStore};
+
+// one tile write, None on either side when the tile was or ends up empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change<T> {
+    pub tile: Coord,
+    pub old: Option<T>,
+    pub new: Option<T>,
+}
+
+// inclusive box of tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub min: Coord,
+    pub max: Coord,
+}
+
+impl Bounds {
+    fn new(c: Coord) -> Bounds {
+        Bounds { min: c, max: c }
+    }
+    fn add(&mut self, c: Coord) {
+        self.min = (self.min.0.min(c.0), self.min.1.min(c.1), self.min.2.min(c.2));
+        self.max = (self.max.0.max(c.0), self.max.1.max(c.1), self.max.2.max(c.2));
+    }
+    fn merge(&mut self, other: Bounds) {
+        self.add(other.min);
+        self.add(other.max);
+    }
+    pub fn contains(&self, c: Coord) -> bool {
+        (self.min.0..=self.max.0).contains(&c.0)
+            && (self.min.1..=self.max.1).contains(&c.1)
+            && (self.min.2..=self.max.2).contains(&c.2)
+    }
+}
+
+// what changed since the last take_dirty, chunks are the 16x16 save chunks
+#[derive(Debug, Clone, Default)]
+pub struct DirtyRegion {
+    // box of the changed tiles in every chunk that had any
+    pub chunks: BTreeMap<(u16, u16), Bounds>,
+    // box around all of them, None when nothing changed
+    pub bounds: Option<Bounds>,
+    pub changes: usize,
+}
+
+impl DirtyRegion {
+    // folds a later region into this one
+    pub fn merge(&mut self, other: DirtyRegion) {
+        for (chunk, b) in other.chunks {
+            self.chunks.entry(chunk).and_modify(|own| own.merge(b)).or_insert(b);
+        }
+        match (&mut self.bounds, other.bounds) {
+            (Some(own), Some(b)) => own.merge(b),
+            (None, b) => self.bounds = b,
+            _ => {}
+        }
+        self.changes += other.changes;
+    }
+}
+
+type Listener<T> = Box<dyn FnMut(&Change<T>)>;
+
+// wraps any backend and reports every write that changes a tile, to a queue that's drained
+// once a tick, to callbacks, or both. writes that leave a tile as it was aren't reported. the
+// changed chunks are always tracked, so a tick can take a dirty region without the queue
+pub struct Observed<M: VoxelStore<T>, T: Clone + PartialEq> {
+    // only None once into_inner took it
+    map: Option<M>,
+    // None while the queue is off
+    events: Option<Vec<Change<T>>>,
+    listeners: Vec<Listener<T>>,
+    dirty: DirtyRegion,
+    // tile handed out by get_mut and its value before, checked on the next call that needs it
+    pending: Option<(Coord, T)>,
+}
+
+impl<M: VoxelStore<T>, T: Clone + PartialEq> Observed<M, T> {
+    pub fn new(map: M) -> Observed<M, T> {
+        Self {
+            map: Some(map),
+            events: None,
+            listeners: Vec::new(),
+            dirty: DirtyRegion::default(),
+            pending: None,
+        }
+    }
+    // same with the event queue on
+    pub fn with_queue(map: M) -> Observed<M, T> {
+        let mut observed = Self::new(map);
+        observed.events = Some(Vec::new());
+        observed
+    }
+    pub fn inner(&self) -> &M {
+        self.map.as_ref().unwrap()
+    }
+    fn inner_mut(&mut self) -> &mut M {
+        self.map.as_mut().unwrap()
+    }
+    // reports the last get_mut write first, like dropping it does
+    pub fn into_inner(mut self) -> M {
+        self.flush();
+        self.map.take().unwrap()
+    }
+    // called with every change as it happens, apart from writes through get_mut, which are
+    // seen on the next write, flush, drain, take_dirty, into_inner or drop
+    pub fn subscribe<F: FnMut(&Change<T>) + 'static>(&mut self, f: F) {
+        self.listeners.push(Box::new(f));
+    }
+    // changes queued since the last drain, oldest first. empty while the queue is off
+    pub fn drain_events(&mut self) -> Vec<Change<T>> {
+        self.flush();
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+    // changed chunks and their boxes since the last call, meant for once a tick
+    pub fn take_dirty(&mut self) -> DirtyRegion {
+        self.flush();
+        std::mem::take(&mut self.dirty)
+    }
+    // reports the last get_mut write if it changed the tile
+    pub fn flush(&mut self) {
+        if let Some(((x, y, z), old)) = self.pending.take() {
+            let new = self.inner().get(x, y, z).cloned();
+            self.emit((x, y, z), Some(old), new);
+        }
+    }
+    fn emit(&mut self, tile: Coord, old: Option<T>, new: Option<T>) {
+        if old == new {
+            return;
+        }
+        let (chunk, _) = split(tile.0, tile.1);
+        self.dirty.chunks.entry(chunk).and_modify(|b| b.add(tile)).or_insert_with(|| Bounds::new(tile));
+        match &mut self.dirty.bounds {
+            Some(b) => b.add(tile),
+            None => self.dirty.bounds = Some(Bounds::new(tile)),
+        }
+        self.dirty.changes += 1;
+        let change = Change { tile, old, new };
+        for f in &mut self.listeners {
+            f(&change);
+        }
+        if let Some(events) = &mut self.events {
+            events.push(change);
+        }
+    }
+}
+
+impl<M: VoxelStore<T>, T: Clone + PartialEq> Drop for Observed<M, T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<M: VoxelStore<T>, T: Clone + PartialEq> VoxelStore<T> for Observed<M, T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        self.inner().get(x, y, z)
+    }
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        self.flush();
+        let old = self.inner().get(x, y, z)?.clone();
+        self.pending = Some(((x, y, z), old));
+        self.inner_mut().get_mut(x, y, z)
+    }
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        self.flush();
+        let new = value.clone();
+        let old = self.inner_mut().insert(x, y, z, value);
+        self.emit((x, y, z), old.clone(), Some(new));
+        old
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        self.flush();
+        let old = self.inner_mut().remove(x, y, z)?;
+        self.emit((x, y, z), Some(old.clone()), None);
+        Some(old)
+    }
+    fn contains(&self, x: u16, y: u16, z: u8) -> bool {
+        self.inner().contains(x, y, z)
+    }
+    fn len(&self) -> usize {
+        self.inner().len()
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        self.inner().iter()
+    }
+    // the map, the queue and the dirty summary
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>() - std::mem::size_of::<Option<M>>()
+            + self.inner().memory_usage()
+            + self.events.as_ref().map_or(0, |e| e.capacity() * std::mem::size_of::<Change<T>>())
+            + self.listeners.capacity() * std::mem::size_of::<Listener<T>>()
+            + btree_memory::<(u16, u16), Bounds>(self.dirty.chunks.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nestedbtree::NestedBTree;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Heard = Rc<RefCell<Vec<Change<u8>>>>;
+
+    // one tile set before anyone listened and the dirty region already taken
+    fn heard() -> (Observed<NestedBTree<u8>, u8>, Heard) {
+        let mut map = Observed::new(NestedBTree::new());
+        map.insert(1, 2, 3, 7);
+        map.take_dirty();
+        let heard = Rc::new(RefCell::new(Vec::new()));
+        let sink = heard.clone();
+        map.subscribe(move |c| sink.borrow_mut().push(c.clone()));
+        (map, heard)
+    }
+
+    #[test]
+    fn last_get_mut_is_reported_on_drop() {
+        let (mut map, heard) = heard();
+        *map.get_mut(1, 2, 3).unwrap() = 8;
+        assert!(heard.borrow().is_empty());
+        drop(map);
+        assert_eq!(*heard.borrow(), [Change { tile: (1, 2, 3), old: Some(7), new: Some(8) }]);
+    }
+
+    #[test]
+    fn last_get_mut_is_reported_by_into_inner() {
+        let (mut map, heard) = heard();
+        *map.get_mut(1, 2, 3).unwrap() = 9;
+        let inner = map.into_inner();
+        assert_eq!(inner.get(1, 2, 3), Some(&9));
+        assert_eq!(*heard.borrow(), [Change { tile: (1, 2, 3), old: Some(7), new: Some(9) }]);
+    }
+
+    #[test]
+    fn unchanged_get_mut_is_not_reported() {
+        let (mut map, heard) = heard();
+        assert_eq!(map.get_mut(1, 2, 3).copied(), Some(7));
+        assert_eq!(map.take_dirty().changes, 0);
+        drop(map);
+        assert!(heard.borrow().is_empty());
+    }
+}
@@ -0,0 +1,435 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::voxelstore::{btree_memory, Coord, VoxelStore};
+
+// every 256 x 256 column of the map is one 256^3 octree, 8 levels deep
+const ROOT_SIZE: u32 = 256;
+
+// local coordinates inside one root
+type Origin = (u32, u32, u32);
+// inclusive box in local coordinates
+type Bounds = (Origin, Origin);
+
+// a uniform region is a single Empty or Leaf node no matter how big it is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Node<T> {
+    Empty,
+    Leaf(T),
+    Branch(Box<[Node<T>; 8]>),
+}
+
+#[inline(always)]
+fn child_index(x: u32, y: u32, z: u32, half: u32) -> usize {
+    ((x & half != 0) as usize) | (((y & half != 0) as usize) << 1) | (((z & half != 0) as usize) << 2)
+}
+
+#[inline(always)]
+fn child_origin(origin: Origin, half: u32, index: usize) -> Origin {
+    (
+        origin.0 + if index & 1 != 0 { half } else { 0 },
+        origin.1 + if index & 2 != 0 { half } else { 0 },
+        origin.2 + if index & 4 != 0 { half } else { 0 },
+    )
+}
+
+#[inline(always)]
+fn overlap(origin: Origin, size: u32, b: &Bounds) -> (bool, bool) {
+    let (min, max) = b;
+    let end = (origin.0 + size - 1, origin.1 + size - 1, origin.2 + size - 1);
+    let disjoint = end.0 < min.0 || origin.0 > max.0
+        || end.1 < min.1 || origin.1 > max.1
+        || end.2 < min.2 || origin.2 > max.2;
+    let covered = origin.0 >= min.0 && end.0 <= max.0
+        && origin.1 >= min.1 && end.1 <= max.1
+        && origin.2 >= min.2 && end.2 <= max.2;
+    (disjoint, covered)
+}
+
+impl<T: Clone + PartialEq> Node<T> {
+    fn from_value(value: Option<T>) -> Node<T> {
+        match value {
+            Some(v) => Node::Leaf(v),
+            None => Node::Empty,
+        }
+    }
+    fn value(&self) -> Option<&T> {
+        match self {
+            Node::Leaf(v) => Some(v),
+            _ => None,
+        }
+    }
+    fn same_as(&self, value: &Option<T>) -> bool {
+        match (self, value) {
+            (Node::Empty, None) => true,
+            (Node::Leaf(v), Some(w)) => v == w,
+            _ => false,
+        }
+    }
+    // boxed children all the way down, the node itself lives in its parent
+    fn memory_usage(&self) -> usize {
+        match self {
+            Node::Branch(children) => {
+                std::mem::size_of::<[Node<T>; 8]>() + children.iter().map(Node::memory_usage).sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+    fn count(&self, size: u32) -> usize {
+        match self {
+            Node::Empty => 0,
+            Node::Leaf(_) => (size as usize).pow(3),
+            Node::Branch(children) => children.iter().map(|c| c.count(size / 2)).sum(),
+        }
+    }
+    // a uniform node becomes 8 copies of itself
+    fn split(&mut self) {
+        if !matches!(self, Node::Branch(_)) {
+            let fill = std::mem::replace(self, Node::Empty);
+            *self = Node::Branch(Box::new(std::array::from_fn(|_| fill.clone())));
+        }
+    }
+    // folds a branch back into one node once all 8 children agree
+    fn collapse(&mut self) {
+        if let Node::Branch(children) = self {
+            let first = &children[0];
+            let uniform = match first {
+                Node::Branch(_) => false,
+                Node::Empty => children.iter().all(|c| matches!(c, Node::Empty)),
+                Node::Leaf(v) => children.iter().all(|c| c.value() == Some(v)),
+            };
+            if uniform {
+                *self = std::mem::replace(&mut children[0], Node::Empty);
+            }
+        }
+    }
+    fn get(&self, x: u32, y: u32, z: u32) -> Option<&T> {
+        let mut node = self;
+        let mut half = ROOT_SIZE / 2;
+        loop {
+            match node {
+                Node::Empty => return None,
+                Node::Leaf(v) => return Some(v),
+                Node::Branch(children) => {
+                    node = &children[child_index(x, y, z, half)];
+                    half /= 2;
+                }
+            }
+        }
+    }
+    fn get_mut(&mut self, x: u32, y: u32, z: u32, size: u32) -> Option<&mut T> {
+        if matches!(self, Node::Empty) {
+            return None;
+        }
+        if size == 1 {
+            return match self {
+                Node::Leaf(v) => Some(v),
+                _ => None,
+            };
+        }
+        // the write has to stay inside this one voxel so uniform nodes get split down to it
+        self.split();
+        match self {
+            Node::Branch(children) => children[child_index(x, y, z, size / 2)].get_mut(x, y, z, size / 2),
+            _ => None,
+        }
+    }
+    fn set(&mut self, x: u32, y: u32, z: u32, size: u32, value: Option<T>) -> Option<T> {
+        if size == 1 {
+            return match std::mem::replace(self, Node::from_value(value)) {
+                Node::Leaf(v) => Some(v),
+                _ => None,
+            };
+        }
+        if self.same_as(&value) {
+            return value;
+        }
+        self.split();
+        let old = match self {
+            Node::Branch(children) => children[child_index(x, y, z, size / 2)].set(x, y, z, size / 2, value),
+            _ => None,
+        };
+        self.collapse();
+        old
+    }
+    // returns how many voxels went from empty to set (negative when clearing)
+    fn fill(&mut self, origin: Origin, size: u32, bounds: &Bounds, value: &Option<T>) -> isize {
+        let (disjoint, covered) = overlap(origin, size, bounds);
+        if disjoint || self.same_as(value) {
+            return 0;
+        }
+        if covered {
+            let before = self.count(size) as isize;
+            *self = Node::from_value(value.clone());
+            return self.count(size) as isize - before;
+        }
+        self.split();
+        let half = size / 2;
+        let delta = match self {
+            Node::Branch(children) => children.iter_mut().enumerate()
+                .map(|(i, c)| c.fill(child_origin(origin, half, i), half, bounds, value))
+                .sum(),
+            _ => 0,
+        };
+        self.collapse();
+        delta
+    }
+    // seen holds the first state found inside the box, every later node has to match it
+    fn uniform<'a>(&'a self, origin: Origin, size: u32, bounds: &Bounds, seen: &mut Option<Option<&'a T>>) -> bool {
+        let (disjoint, _) = overlap(origin, size, bounds);
+        if disjoint {
+            return true;
+        }
+        match self {
+            Node::Branch(children) => {
+                let half = size / 2;
+                children.iter().enumerate().all(|(i, c)| c.uniform(child_origin(origin, half, i), half, bounds, seen))
+            }
+            node => match seen {
+                None => {
+                    *seen = Some(node.value());
+                    true
+                }
+                Some(state) => *state == node.value(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Octree<T> {
+    roots: BTreeMap<(u8, u8), Node<T>>,
+    len: usize,
+}
+
+#[inline(always)]
+fn split(x: u16, y: u16, z: u8) -> ((u8, u8), Origin) {
+    (((x >> 8) as u8, (y >> 8) as u8), ((x & 255) as u32, (y & 255) as u32, z as u32))
+}
+
+impl<T: Clone + PartialEq> Octree<T> {
+    pub fn new() -> Octree<T> {
+        Self {
+            roots: BTreeMap::new(),
+            len: 0,
+        }
+    }
+    // roots that intersect min..=max along with the box in that root's local coordinates
+    fn roots_in(min: Coord, max: Coord) -> impl Iterator<Item = ((u8, u8), Bounds)> {
+        let (rx0, rx1) = (min.0 >> 8, max.0 >> 8);
+        let (ry0, ry1) = (min.1 >> 8, max.1 >> 8);
+        (rx0..=rx1).flat_map(move |rx| (ry0..=ry1).map(move |ry| {
+            let (bx, by) = (rx as u32 * ROOT_SIZE, ry as u32 * ROOT_SIZE);
+            let lo = ((min.0 as u32).max(bx) - bx, (min.1 as u32).max(by) - by, min.2 as u32);
+            let hi = ((max.0 as u32).min(bx + ROOT_SIZE - 1) - bx, (max.1 as u32).min(by + ROOT_SIZE - 1) - by, max.2 as u32);
+            ((rx as u8, ry as u8), (lo, hi))
+        }))
+    }
+    fn fill(&mut self, min: Coord, max: Coord, value: Option<T>) {
+        if min.0 > max.0 || min.1 > max.1 || min.2 > max.2 {
+            return;
+        }
+        for (key, bounds) in Self::roots_in(min, max) {
+            let root = self.roots.entry(key).or_insert(Node::Empty);
+            let delta = root.fill((0, 0, 0), ROOT_SIZE, &bounds, &value);
+            if matches!(root, Node::Empty) {
+                self.roots.remove(&key);
+            }
+            self.len = (self.len as isize + delta) as usize;
+        }
+    }
+    // sets every tile in the inclusive box, whole subtrees collapse into one node
+    pub fn fill_box(&mut self, min: Coord, max: Coord, value: T) {
+        self.fill(min, max, Some(value));
+    }
+    pub fn clear_box(&mut self, min: Coord, max: Coord) {
+        self.fill(min, max, None);
+    }
+    // true when every tile in the inclusive box is empty or every tile holds the same value
+    pub fn is_uniform(&self, min: Coord, max: Coord) -> bool {
+        if min.0 > max.0 || min.1 > max.1 || min.2 > max.2 {
+            return true;
+        }
+        let mut seen = None;
+        let empty = Node::Empty;
+        Self::roots_in(min, max).all(|(key, bounds)| {
+            let root = self.roots.get(&key).unwrap_or(&empty);
+            root.uniform((0, 0, 0), ROOT_SIZE, &bounds, &mut seen)
+        })
+    }
+}
+
+impl<T: Clone + PartialEq> Default for Octree<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + PartialEq> VoxelStore<T> for Octree<T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        let (key, (lx, ly, lz)) = split(x, y, z);
+        self.roots.get(&key)?.get(lx, ly, lz)
+    }
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        let (key, (lx, ly, lz)) = split(x, y, z);
+        self.roots.get_mut(&key)?.get_mut(lx, ly, lz, ROOT_SIZE)
+    }
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        let (key, (lx, ly, lz)) = split(x, y, z);
+        let old = self.roots.entry(key).or_insert(Node::Empty).set(lx, ly, lz, ROOT_SIZE, Some(value));
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        let (key, (lx, ly, lz)) = split(x, y, z);
+        let root = self.roots.get_mut(&key)?;
+        let old = root.set(lx, ly, lz, ROOT_SIZE, None)?;
+        if matches!(root, Node::Empty) {
+            self.roots.remove(&key);
+        }
+        self.len -= 1;
+        Some(old)
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        let stack = self.roots.iter().rev()
+            .map(|(key, node)| (node, (key.0 as u32 * ROOT_SIZE, key.1 as u32 * ROOT_SIZE, 0), ROOT_SIZE))
+            .collect();
+        Box::new(Iter { stack, leaf: None })
+    }
+    fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + btree_memory::<(u8, u8), Node<T>>(self.roots.len())
+            + self.roots.values().map(Node::memory_usage).sum::<usize>()
+    }
+}
+
+// depth first walk, a leaf hands out every voxel it covers
+struct Iter<'a, T> {
+    stack: Vec<(&'a Node<T>, Origin, u32)>,
+    leaf: Option<(&'a T, Origin, u32, u32)>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (Coord, &'a T);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((value, origin, size, i)) = &mut self.leaf {
+                let s = *size;
+                if *i < s * s * s {
+                    let (dx, dy, dz) = (*i % s, (*i / s) % s, *i / (s * s));
+                    *i += 1;
+                    return Some((((origin.0 + dx) as u16, (origin.1 + dy) as u16, (origin.2 + dz) as u8), *value));
+                }
+                self.leaf = None;
+            }
+            let (node, origin, size) = self.stack.pop()?;
+            match node {
+                Node::Empty => {}
+                Node::Leaf(value) => self.leaf = Some((value, origin, size, 0)),
+                Node::Branch(children) => {
+                    let half = size / 2;
+                    for (i, child) in children.iter().enumerate().rev() {
+                        self.stack.push((child, child_origin(origin, half, i), half));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // small box somewhere along each axis, pinned to either limit or a root edge now and then
+    fn span(rng: &mut StdRng, max: u32) -> (u32, u32) {
+        let len = rng.gen_range(1..=20);
+        let start = match rng.gen_range(0..4) {
+            0 => 0,
+            1 => max + 1 - len,
+            2 if max > 255 => (rng.gen_range(1..=max / 256) * 256 - len / 2).min(max + 1 - len),
+            _ => rng.gen_range(0..=max + 1 - len),
+        };
+        (start, start + len - 1)
+    }
+
+    fn random_box(rng: &mut StdRng) -> (Coord, Coord) {
+        let (x0, x1) = span(rng, u16::MAX as u32);
+        let (y0, y1) = span(rng, u16::MAX as u32);
+        let (z0, z1) = span(rng, u8::MAX as u32);
+        ((x0 as u16, y0 as u16, z0 as u8), (x1 as u16, y1 as u16, z1 as u8))
+    }
+
+    fn tiles(min: Coord, max: Coord) -> impl Iterator<Item = Coord> {
+        (min.0..=max.0).flat_map(move |x| (min.1..=max.1).flat_map(move |y| (min.2..=max.2).map(move |z| (x, y, z))))
+    }
+
+    #[test]
+    fn boxes_match_flat_btree() {
+        let mut rng = StdRng::seed_from_u64(0x0C7);
+        let mut tree: Octree<u8> = Octree::new();
+        let mut reference: BTreeMap<Coord, u8> = BTreeMap::new();
+        for _ in 0..400 {
+            let (min, max) = random_box(&mut rng);
+            match rng.gen_range(0..4) {
+                0 => {
+                    tree.clear_box(min, max);
+                    for c in tiles(min, max) {
+                        reference.remove(&c);
+                    }
+                }
+                1 => {
+                    let (x, y, z) = (rng.gen_range(min.0..=max.0), rng.gen_range(min.1..=max.1), rng.gen_range(min.2..=max.2));
+                    let v = rng.gen_range(0..3);
+                    assert_eq!(tree.insert(x, y, z, v), reference.insert((x, y, z), v));
+                }
+                _ => {
+                    let v = rng.gen_range(0..3);
+                    tree.fill_box(min, max, v);
+                    for c in tiles(min, max) {
+                        reference.insert(c, v);
+                    }
+                }
+            }
+            assert_eq!(tree.len(), reference.len());
+            let (min, max) = random_box(&mut rng);
+            let mut values = tiles(min, max).map(|c| reference.get(&c));
+            let first = values.next().unwrap();
+            assert_eq!(tree.is_uniform(min, max), values.all(|v| v == first), "box {:?} ..= {:?}", min, max);
+        }
+        assert!(reference.iter().all(|(c, v)| tree.get(c.0, c.1, c.2) == Some(v)));
+        assert!(tree.iter().all(|(c, v)| reference.get(&c) == Some(v)));
+    }
+
+    #[test]
+    fn boxes_at_the_limits() {
+        let mut tree: Octree<u8> = Octree::new();
+        let (min, max) = ((u16::MAX - 255, u16::MAX - 255, 0), (u16::MAX, u16::MAX, u8::MAX));
+        tree.fill_box(min, max, 3);
+        assert_eq!(tree.len(), 256 * 256 * 256);
+        assert!(tree.is_uniform(min, max));
+        assert!(!tree.is_uniform((u16::MAX - 256, u16::MAX - 255, 0), max));
+        assert_eq!(tree.get(u16::MAX, u16::MAX, u8::MAX), Some(&3));
+        let corner = ((u16::MAX - 1, u16::MAX - 1, u8::MAX - 1), (u16::MAX, u16::MAX, u8::MAX));
+        tree.clear_box(corner.0, corner.1);
+        assert_eq!(tree.len(), 256 * 256 * 256 - 8);
+        assert!(tree.is_uniform(corner.0, corner.1) && !tree.is_uniform(min, max));
+        assert_eq!(tree.get(u16::MAX, u16::MAX, u8::MAX), None);
+        // straddling two roots along x and y from the origin
+        tree.fill_box((0, 0, 0), (256, 256, 0), 1);
+        assert_eq!(tree.len(), 256 * 256 * 256 - 8 + 257 * 257);
+        assert!(tree.is_uniform((0, 0, 0), (256, 256, 0)));
+        tree.clear_box((0, 0, 0), (u16::MAX, u16::MAX, u8::MAX));
+        assert!(tree.is_empty() && tree.is_uniform((0, 0, 0), (u16::MAX, u16::MAX, u8::MAX)));
+        // inverted boxes do nothing
+        tree.fill_box((5, 5, 5), (4, 5, 5), 1);
+        assert!(tree.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+use btree_test::voxelstore::Coord;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// hotspots the clustered pattern builds around, and how far out from the middle it builds
+const FORTRESSES: usize = 8;
+const FORTRESS_RADIUS: i32 = 96;
+// levels above and below a fortress' floor it spreads over
+const FORTRESS_DEPTH: i32 = 12;
+
+// sweeps x then y, with z cycling 0..255. iterations past width * width would run y off the map
+pub fn sweep_coords(width: u16, iterations: u32) -> Vec<Coord> {
+    let mut coords: Vec<Coord> = Vec::with_capacity(iterations as usize);
+    let mut x: u16 = 0;
+    let mut y: u16 = 0;
+    let mut z: u8 = 0;
+    for _ in 0..iterations {
+        if x >= width {
+            x = 0;
+            y += 1;
+        }
+        if z == 255 {
+            z = 0;
+        }
+        coords.push((x, y, z));
+        x += 1;
+        z += 1;
+    }
+    coords
+}
+
+// anywhere in the width x width area, repeats are left in
+fn random_coords(rng: &mut StdRng, width: u16, iterations: u32) -> Vec<Coord> {
+    (0..iterations)
+        .map(|_| (rng.gen_range(0..width), rng.gen_range(0..width), rng.gen_range(0..255)))
+        .collect()
+}
+
+// offset that's usually near 0 and never past radius, the mean of two uniform draws
+fn near(rng: &mut StdRng, radius: i32) -> i32 {
+    (rng.gen_range(-radius..=radius) + rng.gen_range(-radius..=radius)) / 2
+}
+
+#[inline(always)]
+fn clamp(value: i32, max: i32) -> i32 {
+    value.clamp(0, max)
+}
+
+// a few fortresses scattered over the map, each tile lands around one of them so most of the
+// work hits a handful of chunks and levels
+fn clustered_coords(rng: &mut StdRng, width: u16, iterations: u32) -> Vec<Coord> {
+    let max = width as i32 - 1;
+    let fortresses: Vec<(i32, i32, i32)> = (0..FORTRESSES)
+        .map(|_| (rng.gen_range(0..=max), rng.gen_range(0..=max), rng.gen_range(32..224)))
+        .collect();
+    (0..iterations)
+        .map(|_| {
+            let (x, y, z) = fortresses[rng.gen_range(0..fortresses.len())];
+            (
+                clamp(x + near(rng, FORTRESS_RADIUS), max) as u16,
+                clamp(y + near(rng, FORTRESS_RADIUS), max) as u16,
+                clamp(z + near(rng, FORTRESS_DEPTH), 254) as u8,
+            )
+        })
+        .collect()
+}
+
+// digs whole columns from the surface down, each next column beside the last like a mining crew
+// clearing out a room
+fn dig_coords(rng: &mut StdRng, width: u16, iterations: u32) -> Vec<Coord> {
+    let max = width as i32 - 1;
+    let mut coords: Vec<Coord> = Vec::with_capacity(iterations as usize);
+    let (mut x, mut y) = (rng.gen_range(0..=max), rng.gen_range(0..=max));
+    while coords.len() < iterations as usize {
+        for z in (0..255u8).rev() {
+            if coords.len() == iterations as usize {
+                break;
+            }
+            coords.push((x as u16, y as u16, z));
+        }
+        x = clamp(x + rng.gen_range(-1..=1), max);
+        y = clamp(y + rng.gen_range(-1..=1), max);
+    }
+    coords
+}
+
+// random walk that touches every neighbor of the tile it's on before stepping to one of them,
+// the way a pathfinder expands nodes
+fn neighbor_coords(rng: &mut StdRng, width: u16, iterations: u32) -> Vec<Coord> {
+    let max = width as i32 - 1;
+    let mut coords: Vec<Coord> = Vec::with_capacity(iterations as usize);
+    let mut at = (rng.gen_range(0..=max), rng.gen_range(0..=max), rng.gen_range(0..255));
+    let mut neighbors: Vec<(i32, i32, i32)> = Vec::with_capacity(6);
+    while coords.len() < iterations as usize {
+        neighbors.clear();
+        for (dx, dy, dz) in [(-1, 0, 0), (1, 0, 0), (0, -1, 0), (0, 1, 0), (0, 0, -1), (0, 0, 1)] {
+            let (x, y, z) = (at.0 + dx, at.1 + dy, at.2 + dz);
+            if (0..=max).contains(&x) && (0..=max).contains(&y) && (0..255).contains(&z) {
+                neighbors.push((x, y, z));
+            }
+        }
+        for &(x, y, z) in neighbors.iter().take(iterations as usize - coords.len()) {
+            coords.push((x as u16, y as u16, z as u8));
+        }
+        if !neighbors.is_empty() {
+            at = neighbors[rng.gen_range(0..neighbors.len())];
+        }
+    }
+    coords
+}
+
+// order the benchmark touches tiles in, every tile is inserted with its z as the value
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessPattern {
+    Sweep,
+    Random,
+    Clustered,
+    Dig,
+    Neighbors,
+}
+impl AccessPattern {
+    pub const ALL: [AccessPattern; 5] = [
+        AccessPattern::Sweep,
+        AccessPattern::Random,
+        AccessPattern::Clustered,
+        AccessPattern::Dig,
+        AccessPattern::Neighbors,
+    ];
+    pub fn key(self) -> &'static str {
+        match self {
+            AccessPattern::Sweep => "sweep",
+            AccessPattern::Random => "random",
+            AccessPattern::Clustered => "clustered",
+            AccessPattern::Dig => "dig",
+            AccessPattern::Neighbors => "neighbors",
+        }
+    }
+    // the same seed always gives the same coords
+    pub fn coords(self, width: u16, iterations: u32, seed: u64) -> Vec<Coord> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        match self {
+            AccessPattern::Sweep => sweep_coords(width, iterations),
+            AccessPattern::Random => random_coords(&mut rng, width, iterations),
+            AccessPattern::Clustered => clustered_coords(&mut rng, width, iterations),
+            AccessPattern::Dig => dig_coords(&mut rng, width, iterations),
+            AccessPattern::Neighbors => neighbor_coords(&mut rng, width, iterations),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::nestedbtree::{self, Chunk};
+use crate::savefile::{self, Record, SaveError};
+
+// one region file covers 16x16 chunks, which is 256x256 columns
+pub const REGION_CHUNKS: u16 = 16;
+const SLOTS: usize = (REGION_CHUNKS * REGION_CHUNKS) as usize;
+// region header: magic, version u16, then an (offset u64, length u32) slot per chunk.
+// chunk records are the same ones the save file uses, a length of 0 means no chunk
+const REGION_MAGIC: [u8; 4] = *b"DDRG";
+const REGION_VERSION: u16 = 1;
+const INDEX_START: u64 = 6;
+const SLOT_LEN: usize = 12;
+const HEADER_LEN: usize = INDEX_START as usize + SLOTS * SLOT_LEN;
+// region files kept open at once, a scattered map would run out of file handles otherwise
+const MAX_OPEN_REGIONS: usize = 64;
+
+struct RegionFile {
+    file: File,
+    index: Vec<(u64, u32)>,
+    // written since the last sync
+    unsynced: bool,
+}
+
+impl RegionFile {
+    fn open(path: &Path) -> Result<RegionFile, SaveError> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut index = vec![(0, 0); SLOTS];
+        if file.metadata()?.len() == 0 {
+            let mut header = Vec::with_capacity(HEADER_LEN);
+            header.extend_from_slice(&REGION_MAGIC);
+            header.extend_from_slice(&REGION_VERSION.to_le_bytes());
+            header.resize(HEADER_LEN, 0);
+            file.write_all(&header)?;
+        } else {
+            let mut header = vec![0u8; HEADER_LEN];
+            file.read_exact(&mut header)?;
+            if header[0..4] != REGION_MAGIC {
+                return Err(SaveError::BadMagic);
+            }
+            let version = u16::from_le_bytes([header[4], header[5]]);
+            if version > REGION_VERSION {
+                return Err(SaveError::UnsupportedVersion(version));
+            }
+            for (slot, entry) in index.iter_mut().enumerate() {
+                let at = INDEX_START as usize + slot * SLOT_LEN;
+                let offset = u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+                let len = u32::from_le_bytes(header[at + 8..at + 12].try_into().unwrap());
+                *entry = (offset, len);
+            }
+        }
+        Ok(RegionFile { file, index, unsynced: false })
+    }
+    fn read_chunk<T: DeserializeOwned>(&mut self, slot: usize, chunk: (u16, u16)) -> Result<Chunk<T>, SaveError> {
+        let mut data = Chunk::new();
+        let (offset, len) = self.index[slot];
+        if len == 0 {
+            return Ok(data);
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new((&mut self.file).take(len as u64));
+        match savefile::read_record(&mut reader)? {
+            Record::Chunk { chunk: found, tiles } if found == chunk => {
+                for (column, z, value) in tiles {
+                    data.insert(column, z, value);
+                }
+                Ok(data)
+            }
+            _ => Err(SaveError::Corrupt(format!("region slot for chunk {:?} holds something else", chunk))),
+        }
+    }
+    // first offset past the header where len bytes fit without touching a record the index
+    // still points at, the end of the last record if no gap between them is big enough
+    fn free_space(&self, len: u64) -> u64 {
+        let mut live: Vec<(u64, u64)> = self.index.iter()
+            .filter(|e| e.1 != 0)
+            .map(|&(offset, len)| (offset, offset + len as u64))
+            .collect();
+        live.sort_unstable();
+        let mut at = HEADER_LEN as u64;
+        for (start, end) in live {
+            if start >= at + len {
+                break;
+            }
+            at = at.max(end);
+        }
+        at
+    }
+    // writes the record into free space and only then repoints the slot at it, so the old
+    // record stays readable until the index moves on. dead space past the last record is cut off
+    fn write_chunk<T: Serialize>(&mut self, slot: usize, chunk: (u16, u16), data: &Chunk<T>) -> Result<(), SaveError> {
+        let entry = if data.is_empty() {
+            (0, 0)
+        } else {
+            let tiles: Vec<(u8, u8, &T)> = data.iter().map(|((column, z), v)| (column, z, v)).collect();
+            let mut record = Vec::new();
+            savefile::write_chunk(&mut record, chunk, &tiles)?;
+            let offset = self.free_space(record.len() as u64);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&record)?;
+            (offset, record.len() as u32)
+        };
+        let mut bytes = [0u8; SLOT_LEN];
+        bytes[..8].copy_from_slice(&entry.0.to_le_bytes());
+        bytes[8..].copy_from_slice(&entry.1.to_le_bytes());
+        self.file.seek(SeekFrom::Start(INDEX_START + (slot * SLOT_LEN) as u64))?;
+        self.file.write_all(&bytes)?;
+        self.index[slot] = entry;
+        let end = self.index.iter().map(|&(offset, len)| offset + len as u64).max().unwrap_or(0).max(HEADER_LEN as u64);
+        if self.file.metadata()?.len() > end {
+            self.file.set_len(end)?;
+        }
+        self.unsynced = true;
+        Ok(())
+    }
+    fn sync(&mut self) -> Result<(), SaveError> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+}
+
+fn region_path(dir: &Path, key: (u16, u16)) -> PathBuf {
+    dir.join(format!("r.{}.{}.dat", key.0, key.1))
+}
+
+// open region files plus the ones that were written and closed before anyone synced them
+struct Regions {
+    dir: PathBuf,
+    open: HashMap<(u16, u16), RegionFile>,
+    closed_unsynced: BTreeSet<(u16, u16)>,
+}
+
+impl Regions {
+    // region file holding chunk and the chunk's slot in it
+    fn get(&mut self, chunk: (u16, u16)) -> Result<(&mut RegionFile, usize), SaveError> {
+        let key = (chunk.0 / REGION_CHUNKS, chunk.1 / REGION_CHUNKS);
+        let slot = ((chunk.0 % REGION_CHUNKS) * REGION_CHUNKS + chunk.1 % REGION_CHUNKS) as usize;
+        if self.open.len() >= MAX_OPEN_REGIONS && !self.open.contains_key(&key) {
+            // any of them can be closed, reopening is one header read
+            let victim = *self.open.keys().next().unwrap();
+            if self.open.remove(&victim).unwrap().unsynced {
+                self.closed_unsynced.insert(victim);
+            }
+        }
+        let region = match self.open.entry(key) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(RegionFile::open(&region_path(&self.dir, key))?),
+        };
+        Ok((region, slot))
+    }
+    fn sync(&mut self) -> Result<(), SaveError> {
+        for region in self.open.values_mut() {
+            region.sync()?;
+        }
+        while let Some(key) = self.closed_unsynced.pop_first() {
+            File::open(region_path(&self.dir, key))?.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+struct Loaded<T> {
+    chunk: Chunk<T>,
+    dirty: bool,
+    tick: u64,
+}
+
+// map backed by a directory of region files, chunks are paged in on first touch and the
+// least recently used ones are written back and dropped once the memory budget is used up
+pub struct RegionStore<T: Serialize> {
+    budget: usize,
+    used: usize,
+    regions: Regions,
+    loaded: HashMap<(u16, u16), Loaded<T>>,
+    lru: BTreeMap<u64, (u16, u16)>,
+    tick: u64,
+}
+
+impl<T: Serialize + DeserializeOwned> RegionStore<T> {
+    // budget is in bytes, as estimated by Chunk::memory_usage
+    pub fn open<P: AsRef<Path>>(dir: P, budget: usize) -> Result<RegionStore<T>, SaveError> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            budget,
+            used: 0,
+            regions: Regions {
+                dir: dir.as_ref().to_path_buf(),
+                open: HashMap::new(),
+                closed_unsynced: BTreeSet::new(),
+            },
+            loaded: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        })
+    }
+    pub fn loaded_chunks(&self) -> usize {
+        self.loaded.len()
+    }
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+    // makes sure chunk is in memory and the most recently used one
+    fn touch(&mut self, chunk: (u16, u16)) -> Result<&mut Loaded<T>, SaveError> {
+        self.tick += 1;
+        if let Some(loaded) = self.loaded.get_mut(&chunk) {
+            self.lru.remove(&loaded.tick);
+        } else {
+            let (region, slot) = self.regions.get(chunk)?;
+            let data = region.read_chunk(slot, chunk)?;
+            self.used += data.memory_usage();
+            self.loaded.insert(chunk, Loaded { chunk: data, dirty: false, tick: 0 });
+            self.evict(chunk)?;
+        }
+        self.lru.insert(self.tick, chunk);
+        let loaded = self.loaded.get_mut(&chunk).unwrap();
+        loaded.tick = self.tick;
+        Ok(loaded)
+    }
+    // drops least recently used chunks other than keep until the budget fits again
+    fn evict(&mut self, keep: (u16, u16)) -> Result<(), SaveError> {
+        while self.used > self.budget {
+            let victim = match self.lru.values().find(|c| **c != keep) {
+                Some(c) => *c,
+                None => break,
+            };
+            let loaded = self.loaded.remove(&victim).unwrap();
+            self.lru.remove(&loaded.tick);
+            self.used -= loaded.chunk.memory_usage();
+            if loaded.dirty {
+                let (region, slot) = self.regions.get(victim)?;
+                region.write_chunk(slot, victim, &loaded.chunk)?;
+            }
+        }
+        Ok(())
+    }
+    // runs a write against one chunk and keeps the memory estimate in step with it
+    fn write<R>(&mut self, chunk: (u16, u16), f: impl FnOnce(&mut Chunk<T>) -> R) -> Result<R, SaveError> {
+        let loaded = self.touch(chunk)?;
+        let before = loaded.chunk.memory_usage();
+        let result = f(&mut loaded.chunk);
+        loaded.dirty = true;
+        let after = loaded.chunk.memory_usage();
+        self.used = self.used + after - before;
+        self.evict(chunk)?;
+        Ok(result)
+    }
+    pub fn get(&mut self, x: u16, y: u16, z: u8) -> Result<Option<&T>, SaveError> {
+        let (chunk, column) = nestedbtree::split(x, y);
+        Ok(self.touch(chunk)?.chunk.get(column, z))
+    }
+    // the chunk is marked dirty up front since the caller can write through the reference
+    pub fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Result<Option<&mut T>, SaveError> {
+        let (chunk, column) = nestedbtree::split(x, y);
+        let loaded = self.touch(chunk)?;
+        loaded.dirty = true;
+        Ok(loaded.chunk.get_mut(column, z))
+    }
+    pub fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Result<Option<T>, SaveError> {
+        let (chunk, column) = nestedbtree::split(x, y);
+        self.write(chunk, |c| c.insert(column, z, value))
+    }
+    pub fn remove(&mut self, x: u16, y: u16, z: u8) -> Result<Option<T>, SaveError> {
+        let (chunk, column) = nestedbtree::split(x, y);
+        self.write(chunk, |c| c.remove(column, z))
+    }
+    // writes every dirty chunk back to its region file, everything stays loaded
+    pub fn flush(&mut self) -> Result<(), SaveError> {
+        for (chunk, loaded) in self.loaded.iter_mut() {
+            if loaded.dirty {
+                let (region, slot) = self.regions.get(*chunk)?;
+                region.write_chunk(slot, *chunk, &loaded.chunk)?;
+                loaded.dirty = false;
+            }
+        }
+        self.regions.sync()
+    }
+}
+
+// best effort, call flush to find out whether saving worked
+impl<T: Serialize> Drop for RegionStore<T> {
+    fn drop(&mut self) {
+        for (chunk, loaded) in self.loaded.iter() {
+            if loaded.dirty {
+                if let Ok((region, slot)) = self.regions.get(*chunk) {
+                    let _ = region.write_chunk(slot, *chunk, &loaded.chunk);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewritten_chunks_reuse_dead_space() {
+        let dir = std::env::temp_dir().join(format!("btree_test_regions_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let file = region_path(&dir, (0, 0));
+        let mut largest = 0;
+        {
+            let mut store: RegionStore<u32> = RegionStore::open(&dir, usize::MAX).unwrap();
+            for round in 0..100u32 {
+                // two chunks that grow and shrink, so records move around each other
+                for x in [0, 16] {
+                    for z in 0..(round * 7 % 60) as u8 {
+                        store.insert(x, 0, z, round).unwrap();
+                    }
+                    for z in (round * 7 % 60) as u8..60 {
+                        store.remove(x, 0, z).unwrap();
+                    }
+                }
+                store.flush().unwrap();
+                let used = fs::metadata(&file).unwrap().len() - HEADER_LEN as u64;
+                largest = largest.max(used);
+            }
+            store.insert(0, 0, 200, 7).unwrap();
+        }
+        // 60 u32 tiles are a few hundred bytes a chunk, appending would've left tens of kilobytes
+        assert!(largest < 4096, "region file grew to {} bytes past the header", largest);
+        let mut store: RegionStore<u32> = RegionStore::open(&dir, usize::MAX).unwrap();
+        assert_eq!(store.get(0, 0, 200).unwrap(), Some(&7));
+        assert_eq!(store.get(16, 0, 0).unwrap(), Some(&99));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+use std::time::Duration;
+
+// one row of machine readable results, times are nanoseconds across every sample of the op
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BenchResult {
+    pub backend: String,
+    pub op: String,
+    pub iterations: u64,
+    pub samples: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+    pub mean_ns: u64,
+    pub median_ns: u64,
+    pub p95_ns: u64,
+    // what the backend left on disk, none when it didn't save anything
+    pub bytes: Option<u64>,
+    // heap the run took at most and what the map still held at the end, older files don't have them
+    #[serde(default)]
+    pub peak_heap: Option<u64>,
+    #[serde(default)]
+    pub retained_heap: Option<u64>,
+}
+
+impl BenchResult {
+    // samples can't be empty
+    pub fn new(backend: &str, op: &str, iterations: u64, samples: &[Duration], bytes: Option<u64>) -> BenchResult {
+        let mut sorted: Vec<u64> = samples.iter().map(|d| d.as_nanos() as u64).collect();
+        sorted.sort_unstable();
+        Self::from_sorted(backend, op, iterations, &sorted, bytes)
+    }
+    // samples already in ns and ascending, can't be empty
+    pub fn from_sorted(backend: &str, op: &str, iterations: u64, sorted: &[u64], bytes: Option<u64>) -> BenchResult {
+        let n = sorted.len();
+        // nearest rank
+        let rank = |p: u64| sorted[((n as u64 * p).div_ceil(100) as usize).max(1) - 1];
+        Self {
+            backend: backend.to_string(),
+            op: op.to_string(),
+            iterations,
+            samples: n as u64,
+            min_ns: sorted[0],
+            max_ns: sorted[n - 1],
+            mean_ns: sorted.iter().sum::<u64>() / n as u64,
+            median_ns: rank(50),
+            p95_ns: rank(95),
+            bytes,
+            peak_heap: None,
+            retained_heap: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Json,
+    Csv,
+}
+impl Format {
+    pub const ALL: [Format; 2] = [Format::Json, Format::Csv];
+    pub fn key(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+    // csv for a .csv file, json for anything else
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Json,
+        }
+    }
+}
+
+pub fn write(path: &Path, format: Format, results: &[BenchResult]) -> Result<(), String> {
+    let err = |e: &dyn std::fmt::Display| format!("can't write {}: {}", path.display(), e);
+    match format {
+        Format::Json => {
+            let file = BufWriter::new(File::create(path).map_err(|e| err(&e))?);
+            serde_json::to_writer_pretty(file, results).map_err(|e| err(&e))
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_path(path).map_err(|e| err(&e))?;
+            for result in results {
+                writer.serialize(result).map_err(|e| err(&e))?;
+            }
+            writer.flush().map_err(|e| err(&e))
+        }
+    }
+}
+
+// either format, json is told apart by its opening bracket
+pub fn read(path: &Path) -> Result<Vec<BenchResult>, String> {
+    let err = |e: &dyn std::fmt::Display| format!("can't read {}: {}", path.display(), e);
+    let text = fs::read_to_string(path).map_err(|e| err(&e))?;
+    if text.trim_start().starts_with('[') {
+        serde_json::from_str(&text).map_err(|e| err(&e))
+    } else {
+        csv::Reader::from_reader(text.as_bytes()).deserialize().collect::<Result<_, _>>().map_err(|e| err(&e))
+    }
+}
+
+fn change(old: u64, new: u64) -> f64 {
+    if old == 0 {
+        return 0.0;
+    }
+    (new as f64 - old as f64) / old as f64 * 100.0
+}
+
+// lines for every row both sets have (same backend, op and iterations), and how many of them got
+// slower or bigger by more than threshold percent. mean time, file size and heap are compared
+pub fn compare(baseline: &[BenchResult], current: &[BenchResult], threshold: f64) -> (Vec<String>, usize) {
+    let old: BTreeMap<(&str, &str, u64), &BenchResult> = baseline.iter()
+        .map(|r| ((r.backend.as_str(), r.op.as_str(), r.iterations), r))
+        .collect();
+    let mut lines = Vec::new();
+    let mut regressions = 0;
+    for new in current {
+        let Some(old) = old.get(&(new.backend.as_str(), new.op.as_str(), new.iterations)) else {
+            lines.push(format!("{} {}: not in baseline", new.backend, new.op));
+            continue;
+        };
+        let time = change(old.mean_ns, new.mean_ns);
+        let mut regressed = time > threshold;
+        let mut line = format!(
+            "{} {}: mean time {:?} -> {:?} ({:+.1}%)",
+            new.backend, new.op, Duration::from_nanos(old.mean_ns), Duration::from_nanos(new.mean_ns), time
+        );
+        // sizes only get a mention when they moved
+        let sizes = [
+            ("size", old.bytes, new.bytes),
+            ("peak heap", old.peak_heap, new.peak_heap),
+            ("retained heap", old.retained_heap, new.retained_heap),
+        ];
+        for (name, old_bytes, new_bytes) in sizes {
+            if let (Some(old_bytes), Some(new_bytes)) = (old_bytes, new_bytes) {
+                if old_bytes != new_bytes {
+                    let size = change(old_bytes, new_bytes);
+                    regressed |= size > threshold;
+                    line.push_str(&format!(", {} {} -> {} bytes ({:+.1}%)", name, old_bytes, new_bytes, size));
+                }
+            }
+        }
+        if regressed {
+            regressions += 1;
+            line.push_str("  REGRESSION");
+        }
+        lines.push(line);
+    }
+    (lines, regressions)
+}
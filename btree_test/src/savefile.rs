@@ -0,0 +1,481 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use crate::chunkedgrid::ChunkedGrid;
+use crate::codec::{Bincode, Codec, Decode, Encode};
+use crate::columnrle::ColumnRle;
+use crate::genericmap::GenericMap;
+use crate::nestedbtree::{self, DeeplyNestedBTree, NestedBTree};
+use crate::octree::Octree;
+use crate::slottedmap::SlottedMap;
+use crate::voxelstore::{Coord, VoxelStore};
+
+// file layout, all integers little endian:
+//   header: magic, format version u16, backend id u16, dimensions 3 x u32, codec id u8
+//   chunk:  tag 1, cx u16, cy u16, tile count u32, payload length u32, payload, crc32
+//   end:    tag 0, chunk count u32, tile count u64, crc32
+// a chunk is a 16x16 column (same split as NestedBTree), its payload is the (column, z, value)
+// tiles in whatever codec the header names, the crc covers everything in the record after the tag.
+// version 1 files have no codec byte and are always bincode
+pub const MAGIC: [u8; 4] = *b"DDMP";
+pub const FORMAT_VERSION: u16 = 2;
+pub(crate) const TAG_END: u8 = 0;
+pub(crate) const TAG_CHUNK: u8 = 1;
+// a 16x16x256 chunk can't get anywhere near this, anything bigger is a corrupt length
+const MAX_PAYLOAD: u32 = 1 << 30;
+
+// every backend writes the same records, the id just says which one made the file
+pub trait SaveBackend {
+    const BACKEND_ID: u16;
+}
+
+impl<T> SaveBackend for BTreeMap<(u16, u16, u8), T> {
+    const BACKEND_ID: u16 = 1;
+}
+impl<T> SaveBackend for BTreeMap<(u8, u16, u16), T> {
+    const BACKEND_ID: u16 = 2;
+}
+impl<T> SaveBackend for NestedBTree<T> {
+    const BACKEND_ID: u16 = 3;
+}
+impl<T> SaveBackend for DeeplyNestedBTree<T> {
+    const BACKEND_ID: u16 = 4;
+}
+impl<T> SaveBackend for GenericMap<Coord, T> {
+    const BACKEND_ID: u16 = 5;
+}
+impl<T> SaveBackend for SlottedMap<Coord, T> {
+    const BACKEND_ID: u16 = 6;
+}
+impl<T> SaveBackend for ChunkedGrid<T> {
+    const BACKEND_ID: u16 = 7;
+}
+impl<T> SaveBackend for ColumnRle<T> {
+    const BACKEND_ID: u16 = 8;
+}
+impl<T> SaveBackend for Octree<T> {
+    const BACKEND_ID: u16 = 9;
+}
+
+// tiles referenced at once when a backend can't hand out a single chunk
+const WALK_BATCH: usize = 1 << 20;
+
+// how save_with walks a map, every chunk that has tiles in order with its tiles in (column, z)
+// order. backends built out of chunks hand them over one by one, the default makes one pass to
+// count the tiles in each chunk and then one pass per batch of whole chunks
+pub trait SaveChunks<T>: VoxelStore<T> {
+    fn for_each_chunk<'a, F>(&'a self, mut f: F) -> Result<(), SaveError>
+    where
+        T: 'a,
+        F: FnMut((u16, u16), ChunkTiles<'a, T>) -> Result<(), SaveError>,
+    {
+        let mut counts: BTreeMap<(u16, u16), usize> = BTreeMap::new();
+        for ((x, y, _), _) in self.iter() {
+            *counts.entry(nestedbtree::split(x, y).0).or_default() += 1;
+        }
+        let mut rest = counts.into_iter().peekable();
+        while rest.peek().is_some() {
+            let mut batch: BTreeMap<(u16, u16), ChunkTiles<'a, T>> = BTreeMap::new();
+            let mut size = 0;
+            while let Some(&(chunk, count)) = rest.peek() {
+                if !batch.is_empty() && size + count > WALK_BATCH {
+                    break;
+                }
+                batch.insert(chunk, Vec::with_capacity(count));
+                size += count;
+                rest.next();
+            }
+            for ((x, y, z), value) in self.iter() {
+                let (chunk, column) = nestedbtree::split(x, y);
+                if let Some(tiles) = batch.get_mut(&chunk) {
+                    tiles.push((column, z, value));
+                }
+            }
+            for (chunk, mut tiles) in batch {
+                tiles.sort_unstable_by_key(|t| (t.0, t.1));
+                f(chunk, tiles)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> SaveChunks<T> for BTreeMap<(u16, u16, u8), T> {}
+impl<T> SaveChunks<T> for BTreeMap<(u8, u16, u16), T> {}
+impl<T> SaveChunks<T> for DeeplyNestedBTree<T> {}
+impl<T> SaveChunks<T> for GenericMap<Coord, T> {}
+impl<T> SaveChunks<T> for SlottedMap<Coord, T> {}
+impl<T: Clone + PartialEq> SaveChunks<T> for ColumnRle<T> {}
+impl<T: Clone + PartialEq> SaveChunks<T> for Octree<T> {}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    BadMagic,
+    // written by a newer build than this one
+    UnsupportedVersion(u16),
+    WrongBackend { expected: u16, found: u16 },
+    WrongCodec { expected: u8, found: u8 },
+    Checksum { chunk: (u16, u16) },
+    Corrupt(String),
+    // compressed with a codec whose cargo feature is off in this build
+    UnsupportedCompression(u8),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "io error: {}", e),
+            SaveError::BadMagic => write!(f, "not a map save file"),
+            SaveError::UnsupportedVersion(v) => {
+                write!(f, "save format version {} is newer than supported version {}", v, FORMAT_VERSION)
+            }
+            SaveError::WrongBackend { expected, found } => {
+                write!(f, "save was written by backend {}, expected backend {}", found, expected)
+            }
+            SaveError::WrongCodec { expected, found } => {
+                write!(f, "save chunks use codec {}, expected codec {}", found, expected)
+            }
+            SaveError::Checksum { chunk } => write!(f, "checksum mismatch in chunk {:?}", chunk),
+            SaveError::Corrupt(msg) => write!(f, "corrupt save file: {}", msg),
+            SaveError::UnsupportedCompression(id) => {
+                write!(f, "save is compressed with codec {} which this build doesn't include", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        // running out of bytes half way through a record means the file was cut short
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SaveError::Corrupt("unexpected end of file".to_string())
+        } else {
+            SaveError::Io(e)
+        }
+    }
+}
+
+impl From<bincode::Error> for SaveError {
+    fn from(e: bincode::Error) -> Self {
+        SaveError::Corrupt(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub backend: u16,
+    pub dims: (u32, u32, u32),
+    pub codec: u8,
+}
+
+pub fn write_header<W: Write>(writer: &mut W, backend: u16, codec: u8, dims: (u32, u32, u32)) -> Result<(), SaveError> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&backend.to_le_bytes())?;
+    writer.write_all(&dims.0.to_le_bytes())?;
+    writer.write_all(&dims.1.to_le_bytes())?;
+    writer.write_all(&dims.2.to_le_bytes())?;
+    writer.write_all(&[codec])?;
+    Ok(())
+}
+
+pub fn read_header<R: Read>(reader: &mut R) -> Result<Header, SaveError> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(SaveError::BadMagic);
+    }
+    let version = read_u16(reader)?;
+    if version > FORMAT_VERSION {
+        return Err(SaveError::UnsupportedVersion(version));
+    }
+    let backend = read_u16(reader)?;
+    let dims = (read_u32(reader)?, read_u32(reader)?, read_u32(reader)?);
+    let codec = if version >= 2 {
+        let mut codec = [0u8; 1];
+        reader.read_exact(&mut codec)?;
+        codec[0]
+    } else {
+        Bincode::ID
+    };
+    Ok(Header { version, backend, dims, codec })
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+// tiles of one chunk as (column, z, value)
+pub type ChunkTiles<'a, T> = Vec<(u8, u8, &'a T)>;
+
+pub enum Record<T> {
+    Chunk { chunk: (u16, u16), tiles: Vec<(u8, u8, T)> },
+    End { chunks: u32, tiles: u64 },
+}
+
+// bincode record, what journals and region files use
+pub fn write_chunk<T: Serialize, W: Write>(writer: &mut W, chunk: (u16, u16), tiles: &[(u8, u8, &T)]) -> Result<(), SaveError> {
+    write_chunk_with::<Bincode, T, W>(writer, chunk, tiles)
+}
+
+pub fn write_chunk_with<C: Encode<T>, T, W: Write>(writer: &mut W, chunk: (u16, u16), tiles: &[(u8, u8, &T)]) -> Result<(), SaveError> {
+    let mut payload = Vec::new();
+    C::encode(tiles, &mut payload)?;
+    let mut record: Vec<u8> = Vec::with_capacity(12);
+    record.extend_from_slice(&chunk.0.to_le_bytes());
+    record.extend_from_slice(&chunk.1.to_le_bytes());
+    record.extend_from_slice(&(tiles.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&record);
+    crc.update(&payload);
+    writer.write_all(&[TAG_CHUNK])?;
+    writer.write_all(&record)?;
+    writer.write_all(&payload)?;
+    writer.write_all(&crc.finalize().to_le_bytes())?;
+    Ok(())
+}
+
+pub fn write_end<W: Write>(writer: &mut W, chunks: u32, tiles: u64) -> Result<(), SaveError> {
+    let mut end: Vec<u8> = Vec::with_capacity(12);
+    end.extend_from_slice(&chunks.to_le_bytes());
+    end.extend_from_slice(&tiles.to_le_bytes());
+    writer.write_all(&[TAG_END])?;
+    writer.write_all(&end)?;
+    writer.write_all(&crc32fast::hash(&end).to_le_bytes())?;
+    Ok(())
+}
+
+pub fn read_record<T: DeserializeOwned, R: Read>(reader: &mut R) -> Result<Record<T>, SaveError> {
+    read_record_with::<Bincode, T, R>(reader)
+}
+
+// next chunk or end record, checksums are verified here
+pub fn read_record_with<C: Decode<T>, T, R: Read>(reader: &mut R) -> Result<Record<T>, SaveError> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    match tag[0] {
+        TAG_CHUNK => {
+            let mut record = [0u8; 12];
+            reader.read_exact(&mut record)?;
+            let chunk = (
+                u16::from_le_bytes([record[0], record[1]]),
+                u16::from_le_bytes([record[2], record[3]]),
+            );
+            let count = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+            let len = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+            if len > MAX_PAYLOAD {
+                return Err(SaveError::Corrupt(format!("chunk {:?} claims {} bytes", chunk, len)));
+            }
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+            let stored = read_u32(reader)?;
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(&record);
+            crc.update(&payload);
+            if crc.finalize() != stored {
+                return Err(SaveError::Checksum { chunk });
+            }
+            let tiles = C::decode(&payload)?;
+            if tiles.len() != count as usize {
+                return Err(SaveError::Corrupt(format!("chunk {:?} tile count mismatch", chunk)));
+            }
+            Ok(Record::Chunk { chunk, tiles })
+        }
+        TAG_END => {
+            let mut end = [0u8; 12];
+            reader.read_exact(&mut end)?;
+            if crc32fast::hash(&end) != read_u32(reader)? {
+                return Err(SaveError::Corrupt("bad end record".to_string()));
+            }
+            let chunks = u32::from_le_bytes([end[0], end[1], end[2], end[3]]);
+            let tiles = read_u64(&mut &end[4..])?;
+            Ok(Record::End { chunks, tiles })
+        }
+        other => Err(SaveError::Corrupt(format!("unknown record tag {}", other))),
+    }
+}
+
+pub fn save<T, M, W>(map: &M, dims: (u32, u32, u32), writer: W) -> Result<(), SaveError>
+where
+    T: Serialize,
+    M: SaveChunks<T> + SaveBackend,
+    W: Write,
+{
+    save_with::<Bincode, T, M, W>(map, dims, writer)
+}
+
+// streams one record per chunk straight into writer, only one chunk payload is ever buffered
+pub fn save_with<C, T, M, W>(map: &M, dims: (u32, u32, u32), mut writer: W) -> Result<(), SaveError>
+where
+    C: Encode<T>,
+    M: SaveChunks<T> + SaveBackend,
+    W: Write,
+{
+    write_header(&mut writer, M::BACKEND_ID, C::ID, dims)?;
+    let mut chunks: u32 = 0;
+    let mut tiles: u64 = 0;
+    map.for_each_chunk(|chunk, entries| {
+        check_chunk(chunk, entries.iter().map(|t| t.1), dims)?;
+        write_chunk_with::<C, T, W>(&mut writer, chunk, &entries)?;
+        chunks += 1;
+        tiles += entries.len() as u64;
+        Ok(())
+    })?;
+    write_end(&mut writer, chunks, tiles)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// a chunk has to start inside the map and its tiles can't sit above it, which also keeps join
+// from running past u16::MAX
+pub fn check_chunk(chunk: (u16, u16), zs: impl IntoIterator<Item = u8>, dims: (u32, u32, u32)) -> Result<(), SaveError> {
+    let size = nestedbtree::CHUNK_SIZE as u32;
+    let limit = (dims.0.min(1 << 16), dims.1.min(1 << 16));
+    if chunk.0 as u32 * size >= limit.0 || chunk.1 as u32 * size >= limit.1 {
+        return Err(SaveError::Corrupt(format!("chunk {:?} is outside the {:?} map", chunk, dims)));
+    }
+    if let Some(z) = zs.into_iter().find(|&z| z as u32 >= dims.2) {
+        return Err(SaveError::Corrupt(format!("chunk {:?} has a tile at z {} above the {:?} map", chunk, z, dims)));
+    }
+    Ok(())
+}
+
+pub fn load<T, M, R>(reader: R) -> Result<(Header, M), SaveError>
+where
+    T: DeserializeOwned,
+    M: VoxelStore<T> + SaveBackend + Default,
+    R: Read,
+{
+    load_with::<Bincode, T, M, R>(reader)
+}
+
+// reads a file written by save_with for the same backend and codec, every chunk is checked before use
+pub fn load_with<C, T, M, R>(mut reader: R) -> Result<(Header, M), SaveError>
+where
+    C: Decode<T>,
+    M: VoxelStore<T> + SaveBackend + Default,
+    R: Read,
+{
+    let header = read_header(&mut reader)?;
+    if header.backend != M::BACKEND_ID {
+        return Err(SaveError::WrongBackend { expected: M::BACKEND_ID, found: header.backend });
+    }
+    if header.codec != C::ID {
+        return Err(SaveError::WrongCodec { expected: C::ID, found: header.codec });
+    }
+    let mut map = M::default();
+    let mut chunks: u32 = 0;
+    let mut tiles: u64 = 0;
+    loop {
+        match read_record_with::<C, T, R>(&mut reader)? {
+            Record::Chunk { chunk, tiles: entries } => {
+                check_chunk(chunk, entries.iter().map(|t| t.1), header.dims)?;
+                tiles += entries.len() as u64;
+                for (column, z, value) in entries {
+                    let (x, y) = nestedbtree::join(chunk, column);
+                    map.insert(x, y, z, value);
+                }
+                chunks += 1;
+            }
+            Record::End { chunks: expected_chunks, tiles: expected_tiles } => {
+                if expected_chunks != chunks || expected_tiles != tiles {
+                    return Err(SaveError::Corrupt("chunk or tile count mismatch".to_string()));
+                }
+                return Ok((header, map));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn every_backend_writes_the_same_records() {
+        let mut rng = StdRng::seed_from_u64(0x5A7E);
+        let mut nested: NestedBTree<u8> = NestedBTree::new();
+        let mut flat: BTreeMap<(u16, u16, u8), u8> = BTreeMap::new();
+        let mut grid: ChunkedGrid<u8> = ChunkedGrid::new();
+        for _ in 0..20_000 {
+            let (x, y, z, v) = (rng.gen_range(0..200), rng.gen_range(0..200), rng.gen(), rng.gen());
+            nested.insert(x, y, z, v);
+            flat.insert((x, y, z), v);
+            grid.insert(x, y, z, v);
+        }
+        let dims = (200, 200, 256);
+        let bytes = |save: &dyn Fn(&mut Vec<u8>)| {
+            let mut bytes = Vec::new();
+            save(&mut bytes);
+            bytes[8..].to_vec()
+        };
+        let expected = bytes(&|w| save(&nested, dims, w).unwrap());
+        assert_eq!(bytes(&|w| save(&flat, dims, w).unwrap()), expected);
+        assert_eq!(bytes(&|w| save(&grid, dims, w).unwrap()), expected);
+        let mut raw = Vec::new();
+        save(&flat, dims, &mut raw).unwrap();
+        let (_, loaded): (Header, BTreeMap<(u16, u16, u8), u8>) = load(&raw[..]).unwrap();
+        assert_eq!(loaded, flat);
+    }
+
+    #[test]
+    fn chunks_outside_the_map_are_rejected() {
+        for (dims, chunk) in [((32, 32, 256), (2, 0)), ((32, 32, 256), (0, 4095)), ((1 << 20, 1 << 20, 256), (4096, 0))] {
+            let mut raw = Vec::new();
+            write_header(&mut raw, 3, Bincode::ID, dims).unwrap();
+            write_chunk(&mut raw, chunk, &[(0, 0, &1u8)]).unwrap();
+            write_end(&mut raw, 1, 1).unwrap();
+            assert!(matches!(load::<u8, NestedBTree<u8>, _>(&raw[..]), Err(SaveError::Corrupt(_))), "{:?}", chunk);
+        }
+        let mut raw = Vec::new();
+        write_header(&mut raw, 3, Bincode::ID, (u32::MAX, u32::MAX, 256)).unwrap();
+        write_chunk(&mut raw, (4095, 4095), &[(255, 255, &1u8)]).unwrap();
+        write_end(&mut raw, 1, 1).unwrap();
+        let (_, map): (Header, NestedBTree<u8>) = load(&raw[..]).unwrap();
+        assert_eq!(map.get(u16::MAX, u16::MAX, 255), Some(&1));
+    }
+
+    #[test]
+    fn tiles_outside_the_map_are_not_saved() {
+        let mut raw = Vec::new();
+        write_header(&mut raw, 3, Bincode::ID, (32, 32, 100)).unwrap();
+        write_chunk(&mut raw, (1, 1), &[(0, 100, &1u8)]).unwrap();
+        write_end(&mut raw, 1, 1).unwrap();
+        assert!(matches!(load::<u8, NestedBTree<u8>, _>(&raw[..]), Err(SaveError::Corrupt(_))));
+        for (x, y, z) in [(32, 0, 0), (0, 40, 0), (0, 0, 100)] {
+            let mut map: NestedBTree<u8> = NestedBTree::new();
+            map.insert(x, y, z, 1);
+            let result = save(&map, (32, 32, 100), &mut Vec::new());
+            assert!(matches!(result, Err(SaveError::Corrupt(_))), "{:?}", (x, y, z));
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use rayon::prelude::*;
+use std::sync::RwLock;
+use crate::nestedbtree::{split, NestedBTree};
+use crate::voxelstore::{Coord, VoxelStore};
+
+pub const SHARDS: usize = 64;
+
+// nested b-tree split by chunk over SHARDS locks, so worker threads can read and write tiles at
+// the same time and only wait on each other when they land on the same shard. a chunk never
+// spans two shards
+pub struct ShardedMap<T> {
+    shards: Vec<RwLock<NestedBTree<T>>>,
+}
+
+// chunks next to each other land on different shards so a busy area is spread out
+#[inline(always)]
+fn shard_of(x: u16, y: u16) -> usize {
+    let ((cx, cy), _) = split(x, y);
+    (cx as usize * 7 + cy as usize) % SHARDS
+}
+
+// tiles bucketed by the shard they go to
+fn group<T>(tiles: Vec<(Coord, T)>) -> Vec<Vec<(Coord, T)>> {
+    let mut groups: Vec<Vec<(Coord, T)>> = (0..SHARDS).map(|_| Vec::new()).collect();
+    for (c, value) in tiles {
+        groups[shard_of(c.0, c.1)].push((c, value));
+    }
+    groups
+}
+
+// returns how many tiles are new
+fn fill<T: Clone>(shard: &mut NestedBTree<T>, tiles: Vec<(Coord, T)>) -> usize {
+    let mut added = 0;
+    for ((x, y, z), value) in tiles {
+        if shard.insert(x, y, z, value).is_none() {
+            added += 1;
+        }
+    }
+    added
+}
+
+impl<T: Clone> ShardedMap<T> {
+    pub fn new() -> ShardedMap<T> {
+        Self {
+            shards: (0..SHARDS).map(|_| RwLock::new(NestedBTree::new())).collect(),
+        }
+    }
+    // a copy, the shard's lock can't be held past the call
+    pub fn get(&self, x: u16, y: u16, z: u8) -> Option<T> {
+        self.shards[shard_of(x, y)].read().unwrap().get(x, y, z).cloned()
+    }
+    pub fn contains(&self, x: u16, y: u16, z: u8) -> bool {
+        self.shards[shard_of(x, y)].read().unwrap().contains(x, y, z)
+    }
+    pub fn insert(&self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        self.shards[shard_of(x, y)].write().unwrap().insert(x, y, z, value)
+    }
+    pub fn remove(&self, x: u16, y: u16, z: u8) -> Option<T> {
+        self.shards[shard_of(x, y)].write().unwrap().remove(x, y, z)
+    }
+    // read-modify-write under one lock, false when the tile is empty
+    pub fn update<F: FnOnce(&mut T)>(&self, x: u16, y: u16, z: u8, f: F) -> bool {
+        match self.shards[shard_of(x, y)].write().unwrap().get_mut(x, y, z) {
+            Some(value) => {
+                f(value);
+                true
+            }
+            None => false,
+        }
+    }
+    // every shard is locked in turn, so it's only exact while nothing is writing
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.read().unwrap().len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // takes each shard's lock once instead of once per tile, returns how many tiles are new
+    pub fn insert_batch(&self, tiles: Vec<(Coord, T)>) -> usize {
+        group(tiles).into_iter().enumerate()
+            .filter(|(_, tiles)| !tiles.is_empty())
+            .map(|(i, tiles)| fill(&mut self.shards[i].write().unwrap(), tiles))
+            .sum()
+    }
+    // the shards back, one nested b-tree each
+    pub fn into_inner(self) -> Vec<NestedBTree<T>> {
+        self.shards.into_iter().map(|s| s.into_inner().unwrap()).collect()
+    }
+    // shards plus the locks around them
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.shards.iter().map(|s| {
+                std::mem::size_of::<RwLock<NestedBTree<T>>>() - std::mem::size_of::<NestedBTree<T>>()
+                    + s.read().unwrap().memory_usage()
+            }).sum::<usize>()
+    }
+}
+
+impl<T: Clone + Send + Sync> ShardedMap<T> {
+    // like insert_batch with every shard filled on the rayon pool
+    pub fn par_insert_batch(&self, tiles: Vec<(Coord, T)>) -> usize {
+        group(tiles).into_par_iter().enumerate()
+            .filter(|(_, tiles)| !tiles.is_empty())
+            .map(|(i, tiles)| fill(&mut self.shards[i].write().unwrap(), tiles))
+            .sum()
+    }
+    // runs f on every tile with the shards split over the rayon pool, each under its read lock
+    pub fn par_for_each<F: Fn(Coord, &T) + Sync>(&self, f: F) {
+        self.shards.par_iter().for_each(|s| {
+            for (c, v) in s.read().unwrap().iter() {
+                f(c, v);
+            }
+        });
+    }
+}
+
+impl<T: Clone> Default for ShardedMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,40 +1,190 @@
 use serde::{Serialize, Deserialize};
 use slotmap::{DenseSlotMap, SecondaryMap, new_key_type};
+use std::collections::HashMap;
+use std::hash::Hash;
+use crate::voxelstore::{Coord, VoxelStore};
 
 new_key_type! {
-    struct Key;
+    // stable handle for an entry, stays valid (and is never reused) until that entry is removed
+    pub struct Key;
 }
 
+#[derive(Deserialize)]
+struct Slots<K, V> {
+    kmap: DenseSlotMap<Key, K>,
+    vmap: SecondaryMap<Key, V>,
+}
+
+// the key -> slot index isn't saved, it's rebuilt on load
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(from = "Slots<K, V>")]
+#[serde(bound(deserialize = "K: Deserialize<'de> + Hash + Eq + Clone, V: Deserialize<'de>"))]
 pub struct SlottedMap<K,V> {
     kmap: DenseSlotMap<Key,K>,
-    vmap: SecondaryMap<Key,V>
+    vmap: SecondaryMap<Key,V>,
+    #[serde(skip_serializing)]
+    index: HashMap<K, Key>,
 }
 
-impl <K: PartialEq,V> SlottedMap<K, V> {
+impl<K: Hash + Eq + Clone, V> From<Slots<K, V>> for SlottedMap<K, V> {
+    fn from(slots: Slots<K, V>) -> Self {
+        let index = slots.kmap.iter().map(|(handle, k)| (k.clone(), handle)).collect();
+        Self {
+            kmap: slots.kmap,
+            vmap: slots.vmap,
+            index,
+        }
+    }
+}
+
+impl <K: Hash + Eq + Clone,V> SlottedMap<K, V> {
     pub fn new() -> SlottedMap<K, V> {
         Self {
             kmap: DenseSlotMap::with_key(),
             vmap: SecondaryMap::new(),
+            index: HashMap::new(),
         }
     }
     pub fn with_capacity(capacity: usize) -> SlottedMap<K, V> {
         Self {
             kmap: DenseSlotMap::with_capacity_and_key(capacity),
             vmap: SecondaryMap::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
         }
     }
-    pub fn insert(&mut self, key: K, value: V) {
-        let index = self.kmap.insert(key);
-        self.vmap.insert(index, value);
+    // an existing key keeps its handle and just gets the new value
+    pub fn insert(&mut self, key: K, value: V) -> Key {
+        self.replace(key, value).0
+    }
+    // like insert but hands back the value that was there before
+    pub fn replace(&mut self, key: K, value: V) -> (Key, Option<V>) {
+        if let Some(&handle) = self.index.get(&key) {
+            return (handle, self.vmap.insert(handle, value));
+        }
+        let handle = self.kmap.insert(key.clone());
+        self.index.insert(key, handle);
+        self.vmap.insert(handle, value);
+        (handle, None)
+    }
+    pub fn handle(&self, key: &K) -> Option<Key> {
+        self.index.get(key).copied()
+    }
+    pub fn key(&self, handle: Key) -> Option<&K> {
+        self.kmap.get(handle)
     }
     pub fn get(&self, key: &K) -> Option<&V> {
-        //let index = self.kmap.values().find(|x|x==&key);
-        for x in self.kmap.iter() {
-            if &x.1 == &key {
-                return Some(self.vmap.get(x.0).unwrap());
-            }
+        self.vmap.get(*self.index.get(key)?)
+    }
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.vmap.get_mut(*self.index.get(key)?)
+    }
+    pub fn get_by_handle(&self, handle: Key) -> Option<&V> {
+        self.vmap.get(handle)
+    }
+    pub fn get_by_handle_mut(&mut self, handle: Key) -> Option<&mut V> {
+        self.vmap.get_mut(handle)
+    }
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let handle = self.index.remove(key)?;
+        self.kmap.remove(handle);
+        self.vmap.remove(handle)
+    }
+    pub fn remove_by_handle(&mut self, handle: Key) -> Option<(K, V)> {
+        let key = self.kmap.remove(handle)?;
+        self.index.remove(&key);
+        Some((key, self.vmap.remove(handle)?))
+    }
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+    pub fn len(&self) -> usize {
+        self.kmap.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.kmap.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &K, &V)> {
+        self.kmap.iter().map(|(handle, k)| (handle, k, &self.vmap[handle]))
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Default for SlottedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> VoxelStore<T> for SlottedMap<Coord, T> {
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        SlottedMap::get(self, &(x, y, z))
+    }
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        SlottedMap::get_mut(self, &(x, y, z))
+    }
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        self.replace((x, y, z), value).1
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        SlottedMap::remove(self, &(x, y, z))
+    }
+    fn contains(&self, x: u16, y: u16, z: u8) -> bool {
+        self.contains_key(&(x, y, z))
+    }
+    fn len(&self) -> usize {
+        SlottedMap::len(self)
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(SlottedMap::iter(self).map(|(_, k, v)| (*k, v)))
+    }
+    fn memory_usage(&self) -> usize {
+        use std::mem::size_of;
+        // dense map: keys, handles and a (version, index) slot per entry. secondary map: a
+        // version next to each value. the index is a swiss table with a control byte per
+        // bucket, at most 7/8 full
+        size_of::<Self>()
+            + self.kmap.capacity() * (size_of::<Coord>() + size_of::<Key>() + 8)
+            + self.vmap.capacity() * size_of::<(u32, Option<T>)>()
+            + self.index.capacity() * (size_of::<(Coord, Key)>() + 1) * 8 / 7
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_survive_other_removals() {
+        let mut map: SlottedMap<Coord, u32> = SlottedMap::new();
+        let handles: Vec<Key> = (0..100u16).map(|i| map.insert((i, u16::MAX - i, i as u8), i as u32)).collect();
+        for (i, &h) in handles.iter().enumerate().filter(|(i, _)| i % 3 == 0) {
+            let c = (i as u16, u16::MAX - i as u16, i as u8);
+            assert_eq!(map.remove_by_handle(h), Some((c, i as u32)));
+            assert_eq!(map.get_by_handle(h), None);
+            assert_eq!(map.get(&c), None);
+            assert_eq!(map.handle(&c), None);
+            assert_eq!(map.remove_by_handle(h), None);
+        }
+        for (i, &h) in handles.iter().enumerate().filter(|(i, _)| i % 3 != 0) {
+            let c = (i as u16, u16::MAX - i as u16, i as u8);
+            assert_eq!(map.get_by_handle(h), Some(&(i as u32)));
+            assert_eq!(map.handle(&c), Some(h));
+            assert_eq!(map.key(h), Some(&c));
         }
-        None
+        assert_eq!(map.len(), 66);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn removed_handles_are_not_reused() {
+        let mut map: SlottedMap<Coord, u32> = SlottedMap::new();
+        let old = map.insert((0, 0, 0), 1);
+        assert_eq!(map.remove(&(0, 0, 0)), Some(1));
+        let new = map.insert((0, 0, 0), 2);
+        assert_ne!(old, new);
+        assert_eq!(map.get_by_handle(old), None);
+        assert_eq!(map.remove_by_handle(old), None);
+        assert_eq!(map.get_by_handle(new), Some(&2));
+        *map.get_by_handle_mut(new).unwrap() += 1;
+        assert_eq!(map.remove_by_handle(new), Some(((0, 0, 0), 3)));
+        assert!(map.is_empty());
+    }
+}
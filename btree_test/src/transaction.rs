@@ -0,0 +1,331 @@
+use crate::voxelstore::{Coord, VoxelStore};
+
+// what a tile held before an edit, None when it was empty
+type Change<T> = (Coord, Option<T>);
+
+// wraps any backend and logs the value every edit overwrote, so edits can be rolled back or
+// undone. begin opens a transaction, calling it again inside one opens a savepoint that can be
+// rolled back on its own. a committed outermost transaction is one undo step, an edit made
+// outside of any transaction is a step by itself. a get_mut only counts as an edit if the value
+// is different by the next call that needs to know
+pub struct Transactional<M, T> {
+    map: M,
+    // changes of the open transaction, oldest first
+    log: Vec<Change<T>>,
+    // log length when each open begin was called, innermost last
+    savepoints: Vec<usize>,
+    undo: Vec<Vec<Change<T>>>,
+    redo: Vec<Vec<Change<T>>>,
+    // tile handed out by get_mut and its value before
+    pending: Option<(Coord, T)>,
+}
+
+impl<M: VoxelStore<T>, T: Clone + PartialEq> Transactional<M, T> {
+    pub fn new(map: M) -> Transactional<M, T> {
+        Self {
+            map,
+            log: Vec::new(),
+            savepoints: Vec::new(),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            pending: None,
+        }
+    }
+    pub fn inner(&self) -> &M {
+        &self.map
+    }
+    // the map as it is now, open transactions and the undo history are dropped
+    pub fn into_inner(self) -> M {
+        self.map
+    }
+    // open transactions and savepoints, 0 outside of any
+    pub fn depth(&self) -> usize {
+        self.savepoints.len()
+    }
+    pub fn begin(&mut self) {
+        self.settle();
+        self.savepoints.push(self.log.len());
+    }
+    // keeps the innermost open transaction's edits, they become part of the one around it or an
+    // undo step when it's the outermost. false when nothing is open
+    pub fn commit(&mut self) -> bool {
+        self.settle();
+        if self.savepoints.pop().is_none() {
+            return false;
+        }
+        if self.savepoints.is_empty() {
+            let log = std::mem::take(&mut self.log);
+            self.push_step(log);
+        }
+        true
+    }
+    // puts back every tile the innermost open transaction changed. false when nothing is open
+    pub fn rollback(&mut self) -> bool {
+        self.settle();
+        let Some(start) = self.savepoints.pop() else {
+            return false;
+        };
+        let changes = self.log.split_off(start);
+        self.revert(changes);
+        true
+    }
+    // a get_mut write outside of a transaction is a step of its own that clears the redo steps
+    pub fn can_undo(&self) -> bool {
+        self.savepoints.is_empty() && (!self.undo.is_empty() || self.changed())
+    }
+    pub fn can_redo(&self) -> bool {
+        self.savepoints.is_empty() && !self.redo.is_empty() && !self.changed()
+    }
+    // reverts the last step. false when there's none or a transaction is open
+    pub fn undo(&mut self) -> bool {
+        if !self.can_undo() {
+            return false;
+        }
+        self.settle();
+        let step = self.undo.pop().unwrap();
+        let undone = self.revert(step);
+        self.redo.push(undone);
+        true
+    }
+    // applies the last undone step again, any new edit clears these. false when there's none or
+    // a transaction is open
+    pub fn redo(&mut self) -> bool {
+        if !self.can_redo() {
+            return false;
+        }
+        self.settle();
+        let step = self.redo.pop().unwrap();
+        let redone = self.revert(step);
+        self.undo.push(redone);
+        true
+    }
+    // writes the old values back newest first, returns what they replaced in the same shape so
+    // reverting that gets back to where this started
+    fn revert(&mut self, changes: Vec<Change<T>>) -> Vec<Change<T>> {
+        changes.into_iter().rev()
+            .map(|((x, y, z), old)| {
+                let now = match old {
+                    Some(value) => self.map.insert(x, y, z, value),
+                    None => self.map.remove(x, y, z),
+                };
+                ((x, y, z), now)
+            })
+            .collect()
+    }
+    fn push_step(&mut self, changes: Vec<Change<T>>) {
+        if !changes.is_empty() {
+            self.undo.push(changes);
+            self.redo.clear();
+        }
+    }
+    fn changed(&self) -> bool {
+        self.pending.as_ref().is_some_and(|((x, y, z), old)| self.map.get(*x, *y, *z) != Some(old))
+    }
+    // logs the last get_mut if it changed the tile
+    fn settle(&mut self) {
+        if let Some((c, old)) = self.pending.take() {
+            if self.map.get(c.0, c.1, c.2) != Some(&old) {
+                self.record(c, Some(old));
+            }
+        }
+    }
+    fn record(&mut self, c: Coord, old: Option<T>) {
+        if self.savepoints.is_empty() {
+            self.push_step(vec![(c, old)]);
+        } else {
+            self.log.push((c, old));
+        }
+    }
+}
+
+impl<M: VoxelStore<T>, T: Clone + PartialEq> VoxelStore<T> for Transactional<M, T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        self.map.get(x, y, z)
+    }
+    // keeps the value as it is before handing it out, it's logged once something else comes
+    // along and it turns out to have changed
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        self.settle();
+        let old = self.map.get(x, y, z)?.clone();
+        self.pending = Some(((x, y, z), old));
+        self.map.get_mut(x, y, z)
+    }
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        self.settle();
+        let old = self.map.insert(x, y, z, value);
+        self.record((x, y, z), old.clone());
+        old
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        self.settle();
+        let old = self.map.remove(x, y, z)?;
+        self.record((x, y, z), Some(old.clone()));
+        Some(old)
+    }
+    // one change either way, the default would log an insert and then a get_mut
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T {
+        if self.map.contains(x, y, z) {
+            return self.get_mut(x, y, z).unwrap();
+        }
+        self.settle();
+        self.map.insert(x, y, z, default());
+        self.record((x, y, z), None);
+        self.map.get_mut(x, y, z).unwrap()
+    }
+    fn contains(&self, x: u16, y: u16, z: u8) -> bool {
+        self.map.contains(x, y, z)
+    }
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        self.map.iter()
+    }
+    // the map plus every logged change
+    fn memory_usage(&self) -> usize {
+        let change = std::mem::size_of::<Change<T>>();
+        let steps = self.undo.iter().chain(&self.redo).map(|s| s.capacity() * change).sum::<usize>();
+        std::mem::size_of::<Self>() - std::mem::size_of::<M>()
+            + self.map.memory_usage()
+            + self.log.capacity() * change
+            + self.savepoints.capacity() * std::mem::size_of::<usize>()
+            + (self.undo.capacity() + self.redo.capacity()) * std::mem::size_of::<Vec<Change<T>>>()
+            + steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nestedbtree::NestedBTree;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    // random edits in nested transactions with rollbacks, undos and redos, checked against flat
+    // b-tree copies of every state the map should be able to get back to
+    #[test]
+    fn matches_flat_btree_states() {
+        let mut map: Transactional<NestedBTree<u32>, u32> = Transactional::new(NestedBTree::new());
+        let mut reference: BTreeMap<Coord, u32> = BTreeMap::new();
+        // the reference as it was at each open begin, with how many changes had been logged by then
+        let mut saved: Vec<(BTreeMap<Coord, u32>, usize)> = Vec::new();
+        let mut undo: Vec<BTreeMap<Coord, u32>> = Vec::new();
+        let mut redo: Vec<BTreeMap<Coord, u32>> = Vec::new();
+        let mut logged = 0;
+        let mut rng = StdRng::seed_from_u64(0x7A5);
+        for step in 0..20_000 {
+            let roll = rng.gen_range(0..100);
+            let depth = map.depth();
+            if depth == 0 && roll < 60 || depth > 0 && depth < 4 && roll < 3 {
+                map.begin();
+                saved.push((reference.clone(), logged));
+                continue;
+            }
+            if depth == 0 && roll < 70 {
+                assert_eq!(map.undo(), !undo.is_empty());
+                if let Some(state) = undo.pop() {
+                    redo.push(std::mem::replace(&mut reference, state));
+                }
+            } else if depth == 0 && roll < 80 {
+                assert_eq!(map.redo(), !redo.is_empty());
+                if let Some(state) = redo.pop() {
+                    undo.push(std::mem::replace(&mut reference, state));
+                }
+            } else if depth > 0 && roll < 7 {
+                assert!(map.commit());
+                let (before, _) = saved.pop().unwrap();
+                // a savepoint's edits just carry on in the transaction around it
+                if saved.is_empty() && logged > 0 {
+                    undo.push(before);
+                    redo.clear();
+                }
+                if saved.is_empty() {
+                    logged = 0;
+                }
+            } else if depth > 0 && roll < 10 {
+                assert!(map.rollback());
+                (reference, logged) = saved.pop().unwrap();
+            } else {
+                // small area so edits keep landing on tiles that are already there
+                let (x, y, z) = (rng.gen_range(0..128), rng.gen_range(0..128), rng.gen_range(0..8));
+                let before = (depth == 0).then(|| reference.clone());
+                let changed = match rng.gen_range(0..12) {
+                    0..=6 => {
+                        let val = rng.gen();
+                        assert_eq!(map.insert(x, y, z, val), reference.insert((x, y, z), val));
+                        true
+                    }
+                    7 | 8 => {
+                        let old = reference.remove(&(x, y, z));
+                        assert_eq!(map.remove(x, y, z), old);
+                        old.is_some()
+                    }
+                    9 => {
+                        let old = reference.get_mut(&(x, y, z));
+                        let changed = old.is_some();
+                        if let Some(v) = old {
+                            *v += 1;
+                        }
+                        if let Some(v) = map.get_mut(x, y, z) {
+                            *v += 1;
+                        }
+                        changed
+                    }
+                    // handed out but left as it was, that's no edit
+                    10 => {
+                        assert_eq!(map.get_mut(x, y, z).map(|v| *v), reference.get(&(x, y, z)).copied());
+                        false
+                    }
+                    _ => {
+                        if let Some(v) = map.get_mut(x, y, z) {
+                            *v = v.wrapping_add(1);
+                            *v = v.wrapping_sub(1);
+                        }
+                        false
+                    }
+                };
+                if changed {
+                    logged += 1;
+                    // an edit outside a transaction is an undo step by itself
+                    if let Some(before) = before {
+                        undo.push(before);
+                        redo.clear();
+                        logged = 0;
+                    }
+                }
+            }
+            if map.depth() == 0 {
+                assert_eq!(map.can_undo(), !undo.is_empty());
+                assert_eq!(map.can_redo(), !redo.is_empty());
+            }
+            assert_eq!(map.len(), reference.len());
+            if step % 64 == 0 {
+                assert!(map.iter().eq(reference.iter().map(|(c, v)| (*c, v))));
+            }
+        }
+        while map.depth() > 0 {
+            map.rollback();
+            reference = saved.pop().unwrap().0;
+        }
+        assert!(map.inner().iter().eq(reference.iter().map(|(c, v)| (*c, v))));
+    }
+
+    #[test]
+    fn reading_through_get_mut_keeps_redo() {
+        let mut map: Transactional<NestedBTree<u32>, u32> = Transactional::new(NestedBTree::new());
+        map.insert(1, 2, 3, 10);
+        map.insert(1, 2, 3, 20);
+        assert!(map.undo());
+        assert_eq!(map.get_mut(1, 2, 3).copied(), Some(10));
+        assert!(map.can_redo());
+        assert!(map.redo());
+        assert_eq!(map.get(1, 2, 3), Some(&20));
+        *map.get_mut(1, 2, 3).unwrap() = 30;
+        assert!(map.undo());
+        assert_eq!(map.get(1, 2, 3), Some(&20));
+        assert!(map.undo() && map.undo());
+        assert!(map.is_empty() && !map.can_undo());
+    }
+}
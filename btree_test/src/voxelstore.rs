@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+pub type Coord = (u16, u16, u8);
+
+// rough heap size of a b-tree map. nodes have room for 11 entries plus a parent link and
+// lengths, and end up holding about 7 after splits. internal nodes (about one per five
+// below them) carry 12 child pointers on top
+pub fn btree_memory<K, V>(len: usize) -> usize {
+    let node = 11 * (size_of::<K>() + size_of::<V>()) + 16;
+    let leaves = len.div_ceil(7);
+    leaves * node + leaves / 5 * (node + 12 * size_of::<usize>())
+}
+
+/// common interface for every map backend, addressed as x, y, z
+pub trait VoxelStore<T> {
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T>;
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T>;
+    /// returns the previous value if the tile was already set
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T>;
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T>;
+    /// single lookup read-modify-write, inserts `default()` when the tile is empty
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T
+    where
+        Self: Sized,
+    {
+        if !self.contains(x, y, z) {
+            self.insert(x, y, z, default());
+        }
+        self.get_mut(x, y, z).unwrap()
+    }
+    fn contains(&self, x: u16, y: u16, z: u8) -> bool {
+        self.get(x, y, z).is_some()
+    }
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// iteration order is backend specific
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_>;
+    /// estimated bytes the map holds, itself included. values count as their size only, anything
+    /// they point to isn't followed
+    fn memory_usage(&self) -> usize;
+}
+
+// flat b-tree keyed as x,y,z
+impl<T> VoxelStore<T> for BTreeMap<(u16, u16, u8), T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        BTreeMap::get(self, &(x, y, z))
+    }
+    #[inline(always)]
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        BTreeMap::get_mut(self, &(x, y, z))
+    }
+    #[inline(always)]
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        BTreeMap::insert(self, (x, y, z), value)
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        BTreeMap::remove(self, &(x, y, z))
+    }
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T {
+        self.entry((x, y, z)).or_insert_with(default)
+    }
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(BTreeMap::iter(self).map(|(k, v)| (*k, v)))
+    }
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>() + btree_memory::<(u16, u16, u8), T>(BTreeMap::len(self))
+    }
+}
+
+// flat b-tree keyed as z,x,y
+impl<T> VoxelStore<T> for BTreeMap<(u8, u16, u16), T> {
+    #[inline(always)]
+    fn get(&self, x: u16, y: u16, z: u8) -> Option<&T> {
+        BTreeMap::get(self, &(z, x, y))
+    }
+    #[inline(always)]
+    fn get_mut(&mut self, x: u16, y: u16, z: u8) -> Option<&mut T> {
+        BTreeMap::get_mut(self, &(z, x, y))
+    }
+    #[inline(always)]
+    fn insert(&mut self, x: u16, y: u16, z: u8, value: T) -> Option<T> {
+        BTreeMap::insert(self, (z, x, y), value)
+    }
+    fn remove(&mut self, x: u16, y: u16, z: u8) -> Option<T> {
+        BTreeMap::remove(self, &(z, x, y))
+    }
+    fn get_or_insert_with<F: FnOnce() -> T>(&mut self, x: u16, y: u16, z: u8, default: F) -> &mut T {
+        self.entry((z, x, y)).or_insert_with(default)
+    }
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+    fn iter(&self) -> Box<dyn Iterator<Item = (Coord, &T)> + '_> {
+        Box::new(BTreeMap::iter(self).map(|((z, x, y), v)| ((*x, *y, *z), v)))
+    }
+    fn memory_usage(&self) -> usize {
+        size_of::<Self>() + btree_memory::<(u8, u16, u16), T>(BTreeMap::len(self))
+    }
+}
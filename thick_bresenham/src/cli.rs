@@ -0,0 +1,90 @@
+use std::path::PathBuf;
+use crate::report::Format;
+
+pub struct Options {
+    // the line end points are drawn from this so runs repeat exactly
+    pub seed: u64,
+    // times every batch of lines is drawn, each run is one timing sample
+    pub repeat: u32,
+    // results file, format picked from the extension unless given
+    pub output: Option<PathBuf>,
+    pub format: Option<Format>,
+    // earlier results file to check this run against
+    pub compare: Option<PathBuf>,
+    // percent slower than the baseline that counts as a regression
+    pub threshold: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            seed: 0xDEADF00D,
+            repeat: 1,
+            output: None,
+            format: None,
+            compare: None,
+            threshold: 10.0,
+        }
+    }
+}
+
+pub enum Command {
+    Run(Options),
+    Help,
+}
+
+pub fn usage() -> String {
+    let defaults = Options::default();
+    format!(
+        "usage: thick_bresenham [options]
+  --seed <n>            seed for the line end points (default: {})
+  --repeat <n>          runs of every batch of lines (default: {})
+  --output <path>       write the results to a file as well
+  --format <name>       results file format: json, csv (default: from the extension, json otherwise)
+  --compare <path>      flag regressions against an earlier results file, exits with 1 if any
+  --threshold <pct>     percent slower that counts as a regression (default: {})
+  -h, --help            print this and exit",
+        defaults.seed,
+        defaults.repeat,
+        defaults.threshold,
+    )
+}
+
+// args without the program name, flags take their value as the next arg or after '='
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        if flag == "-h" || flag == "--help" {
+            return Ok(Command::Help);
+        }
+        let value = match inline.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(format!("{} needs a value", flag)),
+        };
+        match flag.as_str() {
+            "--seed" => options.seed = number(&flag, &value)?,
+            "--repeat" => options.repeat = number(&flag, &value)?,
+            "--output" => options.output = Some(PathBuf::from(value)),
+            "--format" => {
+                let format = Format::ALL.iter().copied().find(|f| f.key() == value)
+                    .ok_or_else(|| format!("unknown format '{}', expected json or csv", value))?;
+                options.format = Some(format);
+            }
+            "--compare" => options.compare = Some(PathBuf::from(value)),
+            "--threshold" => options.threshold = number(&flag, &value)?,
+            _ => return Err(format!("unknown option '{}'", flag)),
+        }
+    }
+    if options.repeat == 0 {
+        return Err("--repeat has to be at least 1".to_string());
+    }
+    Ok(Command::Run(options))
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a number, got '{}'", flag, value))
+}
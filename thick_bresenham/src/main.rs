@@ -1,91 +1,122 @@
-mod utils;
-use nanorand::Rng;
+mod cli;
+mod report;
+use nanorand::{Rng, WyRand};
+use report::{BenchResult, Format};
 use std::time::{Duration, Instant};
+use thick_bresenham::utils;
 
+// draws every line repeat times, returns how long each run took and the pixel count of a run
+fn time_lines(
+    points1: &[(usize, usize)],
+    points2: &[(usize, usize)],
+    repeat: u32,
+    draw: impl Fn((usize, usize), (usize, usize)) -> Vec<(usize, usize)>,
+) -> (Vec<Duration>, usize) {
+    let mut samples: Vec<Duration> = Vec::with_capacity(repeat as usize);
+    let mut len: usize = 0;
+    for _ in 0..repeat {
+        len = 0;
+        let start = Instant::now();
+        for (&from, &to) in points1.iter().zip(points2) {
+            len += draw(from, to).len();
+        }
+        samples.push(start.elapsed());
+    }
+    (samples, len)
+}
 
-fn test_fat_lines(tests: usize, map_width: usize, line_width: usize) {
+fn test_fat_lines(tests: usize, map_width: usize, line_width: usize, options: &cli::Options) -> Vec<BenchResult> {
     println!("----------------------- [ test begin ] -----------------------");
-    let mut rng = nanorand::tls_rng();
+    let mut rng = WyRand::new_seed(options.seed);
     let mut points1: Vec<(usize, usize)> = vec![];
     let mut points2: Vec<(usize, usize)> = vec![];
-    let mut line: Vec<(usize, usize)>;
-    let mut len: usize = 0;
     for _ in 0..tests {
         points1.push((rng.generate_range(0..map_width), rng.generate_range(0..map_width)));
         points2.push((rng.generate_range(0..map_width), rng.generate_range(0..map_width)));
     }
 
-    let start = Instant::now();
-    for x in 0..tests {
-        line = utils::get_thick_line_unchecked(
-            points1[x],
-            points2[x],
-            line_width,
-            utils::ThicknessMode::LineThicknessMiddle,
-            map_width,
+    let variants: [(&str, &str, Option<bool>); 3] = [
+        ("get_thick_line_unchecked", "get_thick_line_unchecked()", None),
+        ("get_thick_line", "get_thick_line()", Some(false)),
+        ("get_thick_line_unsafe", "get_thick_line(unsafe unchecked)", Some(true)),
+    ];
+    let mut results = vec![];
+    for (backend, label, unsafe_unchecked) in variants {
+        let (samples, len) = time_lines(&points1, &points2, options.repeat, |from, to| match unsafe_unchecked {
+            None => utils::get_thick_line_unchecked(
+                from,
+                to,
+                line_width,
+                utils::ThicknessMode::Middle,
+                map_width,
+            ),
+            Some(unsafe_unchecked) => utils::get_thick_line(
+                from,
+                to,
+                line_width,
+                utils::ThicknessMode::Middle,
+                map_width,
+                unsafe_unchecked,
+            ),
+        });
+        let duration = samples.iter().sum::<Duration>() / samples.len() as u32;
+        println!("{} iterations for {} took: {:?}", tests, label, duration);
+        println!(
+            "total pixels = {}, line width = {}, canvas size = {}x{}\n",
+            len, line_width, map_width, map_width
         );
-        len += line.len();
+        results.push(BenchResult::new(
+            backend,
+            &format!("line width {}", line_width),
+            tests as u64,
+            &samples,
+        ));
     }
-    let duration = start.elapsed();
-    println!(
-        "{} iterations for get_thick_line_unchecked() took: {:?}",
-        tests, duration
-    );
-    println!(
-        "total pixels = {}, line width = {}, canvas size = {}x{}\n",
-        len, line_width, map_width, map_width
-    );
-
+    results
+}
 
-    len = 0;
-    let start = Instant::now();
-    for x in 0..tests {
-        line = utils::get_thick_line(
-            points1[x],
-            points2[x],
-            line_width,
-            utils::ThicknessMode::LineThicknessMiddle,
-            map_width,
-            false,
-        );
-        len += line.len();
+fn main() {
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(options)) => options,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::usage());
+            std::process::exit(2);
+        }
+    };
+    // read before anything runs so a bad path doesn't cost a whole benchmark
+    let baseline = match options.compare.as_deref().map(report::read).transpose() {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let mut results = vec![];
+    results.extend(test_fat_lines(5_000, 1000, 500, &options));
+    results.extend(test_fat_lines(5_000, 1000, 200, &options));
+    results.extend(test_fat_lines(10_000, 1000, 50, &options));
+    results.extend(test_fat_lines(50_000, 1000, 10, &options));
+    if let Some(path) = &options.output {
+        let format = options.format.unwrap_or_else(|| Format::from_path(path));
+        if let Err(e) = report::write(path, format, &results) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        println!("results written to {} as {}", path.display(), format.key());
     }
-    let duration = start.elapsed();
-    println!(
-        "{} iterations for get_thick_line() took: {:?}",
-        tests, duration
-    );
-    println!(
-        "total pixels = {}, line width = {}, canvas size = {}x{}\n",
-        len, line_width, map_width, map_width
-    );
-
-    len = 0;
-    let start = Instant::now();
-    for x in 0..tests {
-        line = utils::get_thick_line(
-            points1[x],
-            points2[x],
-            line_width,
-            utils::ThicknessMode::LineThicknessMiddle,
-            map_width,
-            true,
-        );
-        len += line.len();
+    if let (Some(path), Some(baseline)) = (&options.compare, baseline) {
+        let (lines, regressions) = report::compare(&baseline, &results, options.threshold);
+        println!("\nCompared to {}, threshold = {}%", path.display(), options.threshold);
+        for line in lines {
+            println!("{line}");
+        }
+        println!("{} regressions", regressions);
+        if regressions > 0 {
+            std::process::exit(1);
+        }
     }
-    let duration = start.elapsed();
-    println!(
-        "{} iterations for get_thick_line(unsafe unchecked) took: {:?}",
-        tests, duration
-    );
-    println!(
-        "total pixels = {}, line width = {}, canvas size = {}x{}\n",
-        len, line_width, map_width, map_width
-    );
-}
-fn main() {
-    test_fat_lines(5_000, 1000, 500);
-    test_fat_lines(5_000, 1000, 200);
-    test_fat_lines(10_000, 1000, 50);
-    test_fat_lines(50_000, 1000, 10);
 }
@@ -1,13 +1,12 @@
 const LINE_OVERLAP_NONE: usize = 0; // No line overlap, like in standard Bresenham
 const LINE_OVERLAP_MAJOR: usize = 0x01; // Overlap - first go major then minor direction. Pixel is drawn as extension after actual line
 const LINE_OVERLAP_MINOR: usize = 0x02; // Overlap - first go minor then major direction. Pixel is drawn as extension before next line
-const LINE_OVERLAP_BOTH: usize = 0x03; // Overlap - both
 
 #[derive(PartialEq)]
 pub enum ThicknessMode {
-    LineThicknessMiddle = 0,
-    LineThicknessDrawClockwise = 1,
-    LineThicknessDrawCounterclockwise = 2,
+    Middle = 0,
+    DrawClockwise = 1,
+    DrawCounterclockwise = 2,
 }
 
 #[inline]
@@ -25,8 +24,6 @@ fn get_line_overlap(
     let mut line: Vec<(usize, usize)> = vec![];
     let mut dx: isize;
     let mut dy: isize;
-    let dx2: isize;
-    let dy2: isize;
     let mut err: isize;
     let step_x: isize;
     let step_y: isize;
@@ -45,8 +42,8 @@ fn get_line_overlap(
     } else {
         step_y = 1;
     }
-    dx2 = dx << 1;
-    dy2 = dy << 1;
+    let dx2: isize = dx << 1;
+    let dy2: isize = dy << 1;
     line.push((
         x1.clamp(0, max_width) as usize,
         y1.clamp(0, max_width) as usize,
@@ -121,8 +118,6 @@ fn get_line_overlap2(
     let mut line: Vec<(isize, isize)> = vec![];
     let mut dx: isize;
     let mut dy: isize;
-    let dx2: isize;
-    let dy2: isize;
     let mut err: isize;
     let step_x: isize;
     let step_y: isize;
@@ -140,8 +135,8 @@ fn get_line_overlap2(
     } else {
         step_y = 1;
     }
-    dx2 = dx << 1;
-    dy2 = dy << 1;
+    let dx2: isize = dx << 1;
+    let dy2: isize = dy << 1;
     line.push((x1, y1));
     if dx > dy {
         err = dy2 - dx;
@@ -181,22 +176,6 @@ fn get_line_overlap2(
     line
 }
 
-#[inline]
-fn try_max(x: isize, y: isize, result: &mut bool) -> (usize, usize) {
-    if x < 0 && y < 0 {
-        *result = true;
-        return (0, 0);
-    } else if x < 0 {
-        *result = true;
-        return (0, y as usize);
-    } else if y < 0 {
-        *result = true;
-        return (x as usize, 0);
-    }
-    *result = false;
-    (x as usize, y as usize)
-}
-
 #[inline]
 // ported from https://github.com/ArminJo/Arduino-BlueDisplay/blob/master/src/LocalGUI/ThickLine.hpp
 // i strongly discourage use of the unsafe_unchecked version unless you're 100% sure of what you're doing
@@ -215,8 +194,6 @@ pub fn get_thick_line(
     let mut y2 = to.1 as isize;
     let mut dx: isize;
     let mut dy: isize;
-    let dx2: isize;
-    let dy2: isize;
     let mut err: isize;
     let mut step_x: isize;
     let mut step_y: isize;
@@ -233,15 +210,11 @@ pub fn get_thick_line(
     if y2 > max_width {
         y2 = max_width;
     }
-    fn convert(input: &Vec<(isize, isize)>, max_width: isize) -> Vec<(usize, usize)> {
+    fn convert(input: &[(isize, isize)], max_width: isize) -> Vec<(usize, usize)> {
         let mut retval: Vec<(usize, usize)> = Vec::with_capacity(input.len());
-        for x in 0..input.len() {
-            if input[x].0 >= 0
-                && input[x].0 <= max_width
-                && input[x].1 >= 0
-                && input[x].1 <= max_width
-            {
-                retval.push((input[x].0 as usize, input[x].1 as usize));
+        for &(x, y) in input {
+            if (0..=max_width).contains(&x) && (0..=max_width).contains(&y) {
+                retval.push((x as usize, y as usize));
             }
         }
         retval
@@ -271,13 +244,13 @@ pub fn get_thick_line(
     } else {
         step_y = 1;
     }
-    dx2 = dx << 1;
-    dy2 = dy << 1;
+    let dx2: isize = dx << 1;
+    let dy2: isize = dy << 1;
     let mut overlap: usize;
     let mut draw_start_adjust_count = line_width / 2;
-    if thick_mode == ThicknessMode::LineThicknessDrawCounterclockwise {
+    if thick_mode == ThicknessMode::DrawCounterclockwise {
         draw_start_adjust_count = line_width - 1;
-    } else if thick_mode == ThicknessMode::LineThicknessDrawClockwise {
+    } else if thick_mode == ThicknessMode::DrawClockwise {
         draw_start_adjust_count = 0;
     }
     if dx >= dy {
@@ -378,8 +351,6 @@ pub fn get_thick_line_unchecked(
     let mut y2 = to.1 as isize;
     let mut dx: isize;
     let mut dy: isize;
-    let dx2: isize;
-    let dy2: isize;
     let mut err: isize;
     let mut step_x: isize;
     let mut step_y: isize;
@@ -416,13 +387,13 @@ pub fn get_thick_line_unchecked(
     } else {
         step_y = 1;
     }
-    dx2 = dx << 1;
-    dy2 = dy << 1;
+    let dx2: isize = dx << 1;
+    let dy2: isize = dy << 1;
     let mut overlap: usize;
     let mut draw_start_adjust_count = line_width / 2;
-    if thick_mode == ThicknessMode::LineThicknessDrawCounterclockwise {
+    if thick_mode == ThicknessMode::DrawCounterclockwise {
         draw_start_adjust_count = line_width - 1;
-    } else if thick_mode == ThicknessMode::LineThicknessDrawClockwise {
+    } else if thick_mode == ThicknessMode::DrawClockwise {
         draw_start_adjust_count = 0;
     }
     if dx >= dy {
@@ -546,13 +517,12 @@ pub fn get_line_unchecked(from: (usize, usize), to: (usize, usize)) -> Vec<(usiz
         sy = -1
     }
     let mut err = dx + dy;
-    let mut e2: isize = 0;
     loop {
         line.push((ix0 as usize, iy0 as usize));
         if ix0 == ix1 && iy0 == iy1 {
             break;
         }
-        e2 = 2 * err;
+        let e2 = 2 * err;
         if e2 >= dy {
             err += dy;
             ix0 += sx;
@@ -605,7 +575,6 @@ pub fn get_line(
         sy = -1
     }
     let mut err = dx + dy;
-    let mut e2: isize = 0;
     let mut skip_point: bool;
     loop {
         skip_point = false;
@@ -620,7 +589,7 @@ pub fn get_line(
         if ix0 == ix1 && iy0 == iy1 {
             break;
         }
-        e2 = 2 * err;
+        let e2 = 2 * err;
         if e2 >= dy {
             err += dy;
             ix0 += sx;
@@ -648,31 +617,11 @@ pub fn get_full_circle_naive(
 ) -> Vec<(usize, usize)> {
     let mut full_circle: Vec<(usize, usize)> = vec![];
     let mut nipples: Vec<(usize, usize)> = vec![];
-    let startx: usize;
-    let starty: usize;
-    let endx: usize;
-    let endy: usize;
     let r2 = r as usize;
-    if xm >= r2 {
-        startx = xm - r2;
-    } else {
-        startx = 0;
-    }
-    if ym >= r2 {
-        starty = ym - r2;
-    } else {
-        starty = 0;
-    }
-    if width > xm + r2 {
-        endx = xm + r2
-    } else {
-        endx = width - 1;
-    }
-    if width > ym + r2 {
-        endy = ym + r2;
-    } else {
-        endy = width - 1;
-    }
+    let startx = xm.saturating_sub(r2);
+    let starty = ym.saturating_sub(r2);
+    let endx = if width > xm + r2 { xm + r2 } else { width - 1 };
+    let endy = if width > ym + r2 { ym + r2 } else { width - 1 };
     let mut dist: f32;
     for x in startx..=endx {
         for y in starty..=endy {
@@ -733,7 +682,7 @@ pub fn get_full_circle(xm: usize, ym: usize, mut r: isize, width: usize) -> Vec<
             err += x * 2 + 1;
         }
     }
-    if empty_circle.len() == 0 {
+    if empty_circle.is_empty() {
         return empty_circle;
     }
     // sort by X axis. this allows easy dupe removal and quickly getting coords inside the circle